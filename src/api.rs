@@ -66,17 +66,36 @@
 */
 
 use anyhow as ah;
-use minreq::get;
+use log::{debug, info, trace, warn, Level};
 use serde::de::DeserializeOwned;
+use std::io::Read;
+use std::sync::Arc;
+use std::time::Duration;
 
+use crate::api_error::*;
 use crate::api_models::*;
+use crate::etag::*;
+use crate::logging::*;
+use crate::ratelimit::*;
+use crate::transport::*;
 
-const API_BASE: &str = "https://api.github.com";
+/// The host that repositories are on unless they say otherwise.
+pub const GITHUB_HOST: &str = "github.com";
+
+pub const API_BASE: &str = "https://api.github.com";
+pub const API_VERSION: &str = "2022-11-28";
+pub const USER_AGENT: &str = concat!("github-chronicler/", env!("CARGO_PKG_VERSION"));
 
 typedef!(pub, AuthToken, String);
-typedef!(pub, EndpointURL, String);
-typedef!(pub, EndpointTemplate, String);
-typedef!(pub, URL, String);
+
+pub fn read_token(file: &str) -> ah::Result<AuthToken> {
+    let mut file = std::fs::OpenOptions::new().read(true).open(file)?;
+
+    let mut str: String = String::new();
+    file.read_to_string(&mut str)?;
+
+    Ok(AuthToken(str.trim().to_string()))
+}
 
 #[derive(Debug, Deserialize, Serialize, Clone, Default)]
 pub struct ApiDataReport {
@@ -86,124 +105,672 @@ pub struct ApiDataReport {
     pub daily_clones_model: ModelRepoClonesDaily,
     pub biweekly_referrals_model: ModelReferrerals,
     pub biweekly_content_visits_model: ModelContentTrafficBiWeekly,
+
+    // None if the list couldn't be requested, in which case the report keeps
+    // the one it has. See ApiDataReport::request.
+    #[serde(default)]
+    pub stargazers_model: Option<ModelRepoStargazers>,
+    #[serde(default)]
+    pub forks_model: Option<ModelRepoForks>,
+    #[serde(default)]
+    pub watchers_model: Option<ModelRepoWatchers>,
 }
 impl ApiDataReport {
-    pub fn request(token: &AuthToken, author: &String, repository: &String) -> ah::Result<Self> {
-        let biweekly_views_model = request_views_weekly(token, author, repository)?;
-        let biweekly_clones_model = request_clones_weekly(token, author, repository)?;
-
-        let daily_views_model = request_views_daily(token, author, repository)?;
-        let daily_clones_model = request_clones_daily(token, author, repository)?;
-
-        let biweekly_referrals_model = request_referrers_weekly(token, author, repository)?;
-        let biweekly_content_visits_model =
-            request_popular_paths_weekly(token, author, repository)?;
-
-        Ok(ApiDataReport {
-            biweekly_views_model,
-            biweekly_clones_model,
-            daily_views_model,
-            daily_clones_model,
-            biweekly_referrals_model,
-            biweekly_content_visits_model,
+    /// Requests every endpoint, modified if any of them responded with
+    /// something other than what was cached. The traffic is gone after 14 days
+    /// while the stargazers, forks and watchers can be listed at any time, so
+    /// a list that fails is logged and left out rather than failing the rest.
+    pub fn request(repository: &RepositoryClient) -> ApiResult<Fetched<Self>> {
+        let mut modified = false;
+
+        let biweekly_views_model = repository.views_weekly()?.track(&mut modified);
+        let biweekly_clones_model = repository.clones_weekly()?.track(&mut modified);
+
+        let daily_views_model = repository.views_daily()?.track(&mut modified);
+        let daily_clones_model = repository.clones_daily()?.track(&mut modified);
+
+        let biweekly_referrals_model = repository.referrers_weekly()?.track(&mut modified);
+        let biweekly_content_visits_model = repository.popular_paths_weekly()?.track(&mut modified);
+
+        let stargazers_model = track_list(repository.stargazers(), "stargazers", &mut modified);
+        let forks_model = track_list(repository.forks(), "forks", &mut modified);
+        let watchers_model = track_list(repository.watchers(), "watchers", &mut modified);
+
+        Ok(Fetched {
+            data: ApiDataReport {
+                biweekly_views_model,
+                biweekly_clones_model,
+                daily_views_model,
+                daily_clones_model,
+                biweekly_referrals_model,
+                biweekly_content_visits_model,
+                stargazers_model,
+                forks_model,
+                watchers_model,
+            },
+            modified,
         })
     }
 }
 
-pub fn attempt_api_request<T: DeserializeOwned>(token: &AuthToken, url: &String) -> ah::Result<T> {
-    let request = get(url)
-        .with_header("User-Agent", "PsychedelicShayna")
-        .with_header("Accept", "application/vnd.github+json")
-        .with_header("Authorization", format!("Bearer {}", token.0))
-        .with_header("X-GitHub-Api-Version", "2022-11-28")
-        .with_timeout(2048);
+/// The result of a request, along with whether it differs from the last time
+/// it was requested. Unmodified results were served from the ETag cache, as
+/// the API responded 304 Not Modified.
+#[derive(Debug, Clone)]
+pub struct Fetched<T> {
+    pub data: T,
+    pub modified: bool,
+}
+
+impl<T> Fetched<T> {
+    /// Unwraps the data, noting whether it was modified into the given flag.
+    fn track(self, modified: &mut bool) -> T {
+        *modified |= self.modified;
+        self.data
+    }
+}
+
+/// Like Fetched::track, but for a list that's allowed to fail, which is logged
+/// and comes back as None.
+fn track_list<T>(result: ApiResult<Fetched<T>>, name: &str, modified: &mut bool) -> Option<T> {
+    match result {
+        Ok(fetched) => Some(fetched.track(modified)),
+        Err(error) => {
+            warn!(
+                "Failed to list the {}, keeping the last known: {}",
+                name, error
+            );
+            None
+        }
+    }
+}
+
+/// How to reach the API of a GitHub instance, either github.com or a GitHub
+/// Enterprise Server, as configured under its host name in "hosts".
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct HostConfig {
+    /// The base URL of the REST API, https://{host}/api/v3 if absent, which is
+    /// where a GitHub Enterprise Server serves it.
+    #[serde(default)]
+    pub api_url: Option<String>,
+
+    /// The X-GitHub-Api-Version to request, as servers only support the API
+    /// versions that were current when they were released.
+    #[serde(default)]
+    pub api_version: Option<String>,
+
+    #[serde(default)]
+    pub user_agent: Option<String>,
+}
+
+/// A host, with all of the defaults applied.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ApiHost {
+    /// The host name, which tokens are looked up for in the gh hosts file and
+    /// the git credential helper.
+    pub name: String,
+    pub api_url: String,
+    pub api_version: String,
+    pub user_agent: String,
+}
+
+impl ApiHost {
+    pub fn new(name: &str, config: Option<&HostConfig>) -> Self {
+        let config = config.cloned().unwrap_or(HostConfig {
+            api_url: None,
+            api_version: None,
+            user_agent: None,
+        });
+
+        let api_url = config.api_url.unwrap_or_else(|| match name {
+            GITHUB_HOST => API_BASE.into(),
+            host => format!("https://{}/api/v3", host),
+        });
+
+        ApiHost {
+            name: name.into(),
+            api_url: api_url.trim_end_matches('/').into(),
+            api_version: config.api_version.unwrap_or_else(|| API_VERSION.into()),
+            user_agent: config.user_agent.unwrap_or_else(|| USER_AGENT.into()),
+        }
+    }
+
+    pub fn is_github(&self) -> bool {
+        self.name == GITHUB_HOST
+    }
+}
+
+impl Default for ApiHost {
+    fn default() -> Self {
+        ApiHost::new(GITHUB_HOST, None)
+    }
+}
+
+/// The page size requested from list endpoints, which is the maximum the API
+/// allows. The default of 30 would mean more than three times the requests.
+const PER_PAGE: u32 = 100;
+
+/// How many times a request that was rejected by a rate limit is retried,
+/// after waiting for as long as the API asked.
+const RATE_LIMIT_RETRIES: u32 = 3;
+
+/// How requests are sent, and how persistent to be when they fail.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields, default)]
+pub struct RequestPolicy {
+    /// Seconds to wait for a response before giving up on the attempt.
+    pub timeout: u64,
+
+    /// How many times a transient failure (server errors, network errors and
+    /// timeouts) is retried before giving up on the request.
+    pub retries: u32,
+
+    /// Seconds to wait before the first retry, doubled for every retry after.
+    pub retry_delay: u64,
+
+    /// The longest to wait between two retries, in seconds.
+    pub max_retry_delay: u64,
+}
+
+const DEFAULT_REQUEST_POLICY: RequestPolicy = RequestPolicy {
+    timeout: 60,
+    retries: 4,
+    retry_delay: 2,
+    max_retry_delay: 120,
+};
+
+impl Default for RequestPolicy {
+    fn default() -> Self {
+        DEFAULT_REQUEST_POLICY
+    }
+}
+
+impl RequestPolicy {
+    /// The delay before the given retry, counting from zero, with up to a
+    /// quarter of random jitter so that retries don't all line up.
+    pub fn backoff(&self, retry: u32) -> Duration {
+        let delay = Duration::from_secs(self.retry_delay)
+            .saturating_mul(1 << retry.min(16))
+            .min(Duration::from_secs(self.max_retry_delay));
+
+        delay.mul_f64(1.0 + rand::random::<f64>() / 4.0)
+    }
+}
+
+/// Talks to the API of one host, as one token, sending requests the way the
+/// request policy says. Cheap to create, so one is made whenever a token is
+/// resolved, see RepositoryTarget::client.
+#[derive(Debug, Clone)]
+pub struct Client {
+    pub host: ApiHost,
+    pub token: AuthToken,
+    pub policy: RequestPolicy,
+    pub transport: Arc<dyn Transport>,
+
+    /// The responses received since the last commit_responses.
+    pub staged: Arc<StagedResponses>,
+}
+
+impl Client {
+    pub fn new(host: ApiHost, token: AuthToken, policy: RequestPolicy) -> Self {
+        register_secret(&token.0);
+        Client {
+            host,
+            token,
+            policy,
+            transport: Arc::new(MinreqTransport),
+            staged: Default::default(),
+        }
+    }
+
+    /// Caches the responses received so far, once whatever they hold has been
+    /// saved, see StagedResponses.
+    pub fn commit_responses(&self) {
+        self.staged.commit();
+    }
+
+    /// The endpoints of a single repository.
+    pub fn repository<'a>(&'a self, owner: &'a str, name: &'a str) -> RepositoryClient<'a> {
+        RepositoryClient {
+            client: self,
+            owner,
+            name,
+        }
+    }
+
+    fn url(&self, path: &str) -> String {
+        format!("{}{}", self.host.api_url, path)
+    }
+
+    /// Sends a POST request without a body to an endpoint by its path, e.g.
+    /// /app/installations/{id}/access_tokens, past the ETag cache.
+    pub fn post(&self, path: &str) -> ApiResult<Response> {
+        self.send(Method::Post, &self.url(path), None)
+    }
+
+    fn send(&self, method: Method, url: &String, etag: Option<&str>) -> ApiResult<Response> {
+        let mut rate_limit_retries = 0;
+        let mut retries = 0;
+
+        loop {
+            if let Some(rate_limit) = rate_limit(&self.token) {
+                let pace = rate_limit.pace();
+
+                if pace >= Duration::from_secs(1) {
+                    info!(
+                        "{} out of {} requests left until the rate limit resets, waiting {}s",
+                        rate_limit.remaining,
+                        rate_limit.limit,
+                        pace.as_secs()
+                    );
+                }
+
+                std::thread::sleep(pace);
+            }
+
+            let error = match self.attempt_send(method, url, etag) {
+                Ok(response) => return Ok(response),
+                Err(error) => error,
+            };
+
+            let delay = match &error {
+                ApiError::RateLimited { retry_after, .. }
+                    if rate_limit_retries < RATE_LIMIT_RETRIES =>
+                {
+                    rate_limit_retries += 1;
+                    *retry_after
+                }
+                ApiError::RateLimited { .. } => return Err(error),
+                error if error.is_transient() && retries < self.policy.retries => {
+                    retries += 1;
+                    self.policy.backoff(retries - 1)
+                }
+                _ => return Err(error),
+            };
+
+            warn!("{}, retrying in {}s", error, delay.as_secs());
+            std::thread::sleep(delay);
+        }
+    }
+
+    fn attempt_send(
+        &self,
+        method: Method,
+        url: &String,
+        etag: Option<&str>,
+    ) -> ApiResult<Response> {
+        let mut headers = vec![
+            ("User-Agent", self.host.user_agent.clone()),
+            ("Accept", "application/vnd.github+json".to_string()),
+            ("Authorization", format!("Bearer {}", self.token.0)),
+            ("X-GitHub-Api-Version", self.host.api_version.clone()),
+        ];
+
+        if let Some(etag) = etag {
+            headers.push(("If-None-Match", etag.to_string()));
+        }
+
+        debug!("{}: {}", method, url);
+
+        if logs(Level::Trace) {
+            for (name, value) in &headers {
+                trace!("> {}: {}", name, redact_header(name, value));
+            }
+        }
+
+        let response = self.transport.send(&Request {
+            method,
+            url: url.clone(),
+            headers,
+            timeout: self.policy.timeout,
+        })?;
 
-    println!("Using token: {}", token.0);
-    println!("Sending request {:?}", request);
+        debug!(
+            "{}: {} responded with {} {}",
+            method, url, response.status_code, response.reason_phrase
+        );
 
-    let response = request.send()?;
+        if logs(Level::Trace) {
+            for (name, value) in &response.headers {
+                trace!("< {}: {}", name, redact_header(name, value));
+            }
+        }
+
+        record_rate_limit(&self.token, &response);
+
+        match (response.status_code, etag) {
+            (200..=299, _) | (304, Some(_)) => Ok(response),
+            _ => Err(ApiError::from_response(url, &response)),
+        }
+    }
 
-    println!("Received response {:?}", response);
+    /// Sends a conditional request if there's a cached response for the URL,
+    /// and returns the cached response if the API says it's still current.
+    /// Otherwise the new response is staged to be cached, provided that it came
+    /// with an ETag.
+    fn send_cached(&self, url: &String) -> ApiResult<Fetched<CachedResponse>> {
+        let key = cache_key(&self.token.0, url);
+        let cached = cached_response(&key);
+        let etag = cached.as_ref().map(|c| c.etag.as_str());
+        let response = self.send(Method::Get, url, etag)?;
 
-    let status_code: &i32 = &response.status_code;
+        if let (304, Some(cached)) = (response.status_code, cached) {
+            return Ok(Fetched {
+                data: cached,
+                modified: false,
+            });
+        }
 
-    std::thread::sleep(std::time::Duration::from_millis(128));
+        let fresh = CachedResponse {
+            etag: find_header(&response, "ETag").cloned().unwrap_or_default(),
+            body: response
+                .as_str()
+                .map_err(|e| ApiError::decode(url, e))?
+                .to_string(),
+            link: find_header(&response, "Link").cloned(),
+        };
 
-    match status_code {
-        200 => {
-            let content = response.as_str()?.to_string();
-            let deserialized: T = sj::from_str::<T>(&content).map_err(|e| ah::anyhow!(e))?;
-            Ok(deserialized)
+        if !fresh.etag.is_empty() {
+            self.staged.stage(&key, fresh.clone());
         }
-        code => Err(ah::anyhow!(
-            "GET: {}\nResponse: Request failed with status code {}, {}, {:?}",
-            url,
-            code,
-            response.reason_phrase,
-            response.as_str()?
-        )),
+
+        Ok(Fetched {
+            data: fresh,
+            modified: true,
+        })
+    }
+
+    /// Requests an endpoint by its path, e.g. /repos/{owner}/{repo}/forks.
+    pub fn get<T: DeserializeOwned>(&self, path: &str) -> ApiResult<Fetched<T>> {
+        let url = self.url(path);
+        let Fetched { data, modified } = self.send_cached(&url)?;
+        let data = sj::from_str::<T>(&data.body).map_err(|e| ApiError::decode(&url, e))?;
+        Ok(Fetched { data, modified })
+    }
+
+    /// Requests every page of a list endpoint, following the rel="next" links
+    /// in the Link header of each response, and concatenates them. Every page
+    /// is cached on its own, and the list is modified if any of its pages are.
+    pub fn get_paginated<T: DeserializeOwned>(&self, path: &str) -> ApiResult<Fetched<Vec<T>>> {
+        let url = self.url(path);
+        let separator = if url.contains('?') { '&' } else { '?' };
+        let mut next_url = Some(format!("{}{}per_page={}", url, separator, PER_PAGE));
+        let mut items: Vec<T> = Vec::new();
+        let mut modified = false;
+
+        while let Some(url) = next_url {
+            let response = self.send_cached(&url)?;
+            modified |= response.modified;
+
+            next_url = response.data.link.as_deref().and_then(next_page_link);
+
+            let page: Vec<T> =
+                sj::from_str(&response.data.body).map_err(|e| ApiError::decode(&url, e))?;
+
+            items.extend(page);
+        }
+
+        Ok(Fetched {
+            data: items,
+            modified,
+        })
+    }
+
+    pub fn user_repositories(&self) -> ApiResult<ModelRepositories> {
+        self.get_paginated("/user/repos?affiliation=owner,collaborator,organization_member")
+            .map(|fetched| fetched.data)
     }
+
+    pub fn org_repositories(&self, org: &str) -> ApiResult<ModelRepositories> {
+        self.get_paginated(&format!("/orgs/{}/repos?type=all", org))
+            .map(|fetched| fetched.data)
+    }
+}
+
+/// Header names are compared case insensitively, as minreq keeps whatever
+/// casing the server sent them in.
+pub fn find_header<'a>(response: &'a Response, name: &str) -> Option<&'a String> {
+    response
+        .headers
+        .iter()
+        .find(|(key, _)| key.eq_ignore_ascii_case(name))
+        .map(|(_, value)| value)
+}
+
+/// Extracts the URL of the next page from a Link header, which looks like:
+/// <https://api.github.com/...&page=2>; rel="next", <https://...>; rel="last"
+fn next_page_link(link: &str) -> Option<String> {
+    link.split(',').find_map(|part| {
+        let (url, parameters) = part.split_once(';')?;
+
+        let is_next = parameters
+            .split(';')
+            .any(|parameter| parameter.trim() == "rel=\"next\"");
+
+        let url = url.trim().strip_prefix('<')?.strip_suffix('>')?;
+        is_next.then(|| url.to_string())
+    })
+}
+
+/// A repository as the token sees it, along with the scopes of the token,
+/// which the API only reports for classic personal access tokens.
+pub struct RepositoryAccess {
+    pub repository: ModelRepository,
+    pub scopes: Option<String>,
+}
+
+/// A Client scoped to a single repository, see Client::repository.
+#[derive(Debug, Clone, Copy)]
+pub struct RepositoryClient<'a> {
+    pub client: &'a Client,
+    pub owner: &'a str,
+    pub name: &'a str,
 }
 
 macro_rules! define_request_fn {
     ($name:ident, $type:ty, $endpoint:expr) => {
-        pub fn $name(token: &AuthToken, author: &String, repo: &String) -> ah::Result<$type> {
-            let endpoint = format!($endpoint, API_BASE, author, repo);
-            attempt_api_request(token, &endpoint)
+        pub fn $name(&self) -> ApiResult<Fetched<$type>> {
+            self.client.get(&format!($endpoint, self.owner, self.name))
+        }
+    };
+}
+
+macro_rules! define_paginated_request_fn {
+    ($name:ident, $type:ty, $endpoint:expr) => {
+        pub fn $name(&self) -> ApiResult<Fetched<$type>> {
+            self.client
+                .get_paginated(&format!($endpoint, self.owner, self.name))
         }
     };
 }
 
-define_request_fn!(
-    request_clones_daily,
-    ModelRepoClonesDaily,
-    "{}/repos/{}/{}/traffic/clones?per=day"
-);
-
-define_request_fn!(
-    request_clones_weekly,
-    ModelRepoClonesBiWeekly,
-    "{}/repos/{}/{}/traffic/clones?per=week"
-);
-
-define_request_fn!(
-    request_views_daily,
-    ModelRepoViewsDaily,
-    "{}/repos/{}/{}/traffic/views?per=day"
-);
-
-define_request_fn!(
-    request_views_weekly,
-    ModelRepoViewsBiWeekly,
-    "{}/repos/{}/{}/traffic/views?per=week"
-);
-
-define_request_fn!(
-    request_referrers_weekly,
-    ModelReferrerals,
-    "{}/repos/{}/{}/traffic/popular/referrers"
-);
-
-define_request_fn!(
-    request_popular_paths_weekly,
-    ModelContentTrafficBiWeekly,
-    "{}/repos/{}/{}/traffic/popular/paths"
-);
-
-define_request_fn!(
-    request_stargazers,
-    ModelRepoStargazers,
-    "{}/repos/{}/{}/stargazers"
-);
-
-define_request_fn!(
-    request_forks,
-    ModelRepoForks,
-    "{}/repos/{}/{}/forks"
-);
-
-define_request_fn!(
-    request_watchers,
-    ModelRepoWatchers,
-    "{}/repos/{}/{}/subscribers"
-);
+impl RepositoryClient<'_> {
+    /// Requests /repos/{owner}/{repo} past the ETag cache, as the scopes are
+    /// only of interest when they're current.
+    pub fn access(&self) -> ApiResult<RepositoryAccess> {
+        let url = self
+            .client
+            .url(&format!("/repos/{}/{}", self.owner, self.name));
+        let response = self.client.send(Method::Get, &url, None)?;
+
+        let content = response.as_str().map_err(|e| ApiError::decode(&url, e))?;
+        let repository = sj::from_str(content).map_err(|e| ApiError::decode(&url, e))?;
+
+        Ok(RepositoryAccess {
+            repository,
+            scopes: find_header(&response, "X-OAuth-Scopes").cloned(),
+        })
+    }
+
+    define_request_fn!(
+        clones_daily,
+        ModelRepoClonesDaily,
+        "/repos/{}/{}/traffic/clones?per=day"
+    );
+
+    define_request_fn!(
+        clones_weekly,
+        ModelRepoClonesBiWeekly,
+        "/repos/{}/{}/traffic/clones?per=week"
+    );
+
+    define_request_fn!(
+        views_daily,
+        ModelRepoViewsDaily,
+        "/repos/{}/{}/traffic/views?per=day"
+    );
+
+    define_request_fn!(
+        views_weekly,
+        ModelRepoViewsBiWeekly,
+        "/repos/{}/{}/traffic/views?per=week"
+    );
+
+    define_request_fn!(
+        referrers_weekly,
+        ModelReferrerals,
+        "/repos/{}/{}/traffic/popular/referrers"
+    );
+
+    define_request_fn!(
+        popular_paths_weekly,
+        ModelContentTrafficBiWeekly,
+        "/repos/{}/{}/traffic/popular/paths"
+    );
+
+    define_paginated_request_fn!(stargazers, ModelRepoStargazers, "/repos/{}/{}/stargazers");
+
+    define_paginated_request_fn!(forks, ModelRepoForks, "/repos/{}/{}/forks");
+
+    define_paginated_request_fn!(watchers, ModelRepoWatchers, "/repos/{}/{}/subscribers");
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_next_page_link() {
+        let link = "<https://api.github.com/repositories/1/stargazers?per_page=100&page=2>; rel=\"next\", \
+                    <https://api.github.com/repositories/1/stargazers?per_page=100&page=5>; rel=\"last\""
+            .to_string();
+
+        assert_eq!(
+            next_page_link(&link).as_deref(),
+            Some("https://api.github.com/repositories/1/stargazers?per_page=100&page=2")
+        );
+    }
+
+    #[test]
+    fn test_next_page_link_last_page() {
+        let link = "<https://api.github.com/repositories/1/stargazers?per_page=100&page=4>; rel=\"prev\", \
+                    <https://api.github.com/repositories/1/stargazers?per_page=100&page=1>; rel=\"first\""
+            .to_string();
+
+        assert_eq!(next_page_link(&link), None);
+    }
+
+    #[test]
+    fn test_api_host_defaults() {
+        let github = ApiHost::default();
+        assert_eq!(github.api_url, "https://api.github.com");
+        assert_eq!(github.api_version, API_VERSION);
+
+        let enterprise = ApiHost::new("github.example.com", None);
+        assert_eq!(enterprise.api_url, "https://github.example.com/api/v3");
+        assert!(!enterprise.is_github());
+
+        let config = HostConfig {
+            api_url: Some("https://ghe.internal/api/v3/".into()),
+            api_version: Some("2022-08-09".into()),
+            user_agent: None,
+        };
+
+        let configured = ApiHost::new("github.example.com", Some(&config));
+        assert_eq!(configured.api_url, "https://ghe.internal/api/v3");
+        assert_eq!(configured.api_version, "2022-08-09");
+        assert_eq!(configured.user_agent, USER_AGENT);
+    }
+
+    fn replay_client(token: &str, retries: u32, fixtures: serde_json::Value) -> Client {
+        let replay = ReplayTransport::default();
+
+        for fixture in sj::from_value::<Vec<Fixture>>(fixtures).unwrap() {
+            replay.add(fixture);
+        }
+
+        Client {
+            host: ApiHost::default(),
+            token: AuthToken(token.into()),
+            policy: RequestPolicy {
+                retries,
+                retry_delay: 0,
+                ..Default::default()
+            },
+            transport: Arc::new(replay),
+            staged: Default::default(),
+        }
+    }
+
+    #[test]
+    fn test_transient_errors_are_retried() {
+        let url = "https://api.github.com/repos/octocat/flaky/traffic/views?per=day";
+
+        let client = replay_client(
+            "replay-transient",
+            2,
+            sj::json!([
+                { "url": url, "status": 502, "body": "Bad Gateway" },
+                { "url": url, "body": { "count": 1, "uniques": 1, "views": [] } }
+            ]),
+        );
+
+        let views = client.repository("octocat", "flaky").views_daily().unwrap();
+        assert_eq!(views.data.count, 1);
+
+        let client = replay_client(
+            "replay-transient-exhausted",
+            0,
+            sj::json!([{ "url": url, "status": 503, "body": "Unavailable" }]),
+        );
+
+        let error = client
+            .repository("octocat", "flaky")
+            .views_daily()
+            .unwrap_err();
+        assert!(matches!(error, ApiError::Server { status: 503, .. }));
+    }
+
+    #[test]
+    fn test_errors_are_classified() {
+        let base = "https://api.github.com/repos/octocat";
+
+        let client = replay_client(
+            "replay-errors",
+            4,
+            sj::json!([
+                { "url": format!("{}/gone/forks?per_page=100", base), "status": 404 },
+                { "url": format!("{}/private/traffic/popular/paths", base), "status": 403,
+                  "body": { "message": "Must have push access to repository" } },
+                { "url": format!("{}/revoked/traffic/popular/referrers", base), "status": 401 },
+                { "url": format!("{}/garbled/traffic/clones?per=day", base), "body": [1, 2, 3] }
+            ]),
+        );
+
+        let forks = client.repository("octocat", "gone").forks();
+        let paths = client
+            .repository("octocat", "private")
+            .popular_paths_weekly();
+        let referrers = client.repository("octocat", "revoked").referrers_weekly();
+        let clones = client.repository("octocat", "garbled").clones_daily();
+
+        assert!(matches!(forks.unwrap_err(), ApiError::NotFound { .. }));
+        assert!(matches!(paths.unwrap_err(), ApiError::Forbidden { .. }));
+        assert!(matches!(
+            referrers.unwrap_err(),
+            ApiError::Unauthorized { .. }
+        ));
+        assert!(matches!(clones.unwrap_err(), ApiError::Decode { .. }));
+    }
+}
@@ -0,0 +1,153 @@
+use std::time::Duration;
+
+use crate::ratelimit::*;
+use crate::transport::*;
+
+/// Why a request to the API failed, classified so that callers can tell e.g. a
+/// repository that's gone, or a token without push access, apart from a flaky
+/// response that's worth retrying.
+#[derive(Debug, Clone)]
+pub enum ApiError {
+    /// 401, the token is invalid, expired, or revoked.
+    Unauthorized { url: String, message: String },
+
+    /// 403, the token is valid, but lacks the permissions for the endpoint,
+    /// e.g. push access for the traffic endpoints.
+    Forbidden { url: String, message: String },
+
+    /// 404, the repository doesn't exist, or the token can't see it.
+    NotFound { url: String },
+
+    /// 403 or 429 due to a rate limit, with how long the API asked to wait.
+    RateLimited { url: String, retry_after: Duration },
+
+    /// 5xx, something went wrong on GitHub's end.
+    Server {
+        url: String,
+        status: i32,
+        message: String,
+    },
+
+    /// Any other status code that isn't a success.
+    Unexpected {
+        url: String,
+        status: i32,
+        message: String,
+    },
+
+    /// The request didn't get a response, e.g. a timeout or DNS failure.
+    Network { url: String, message: String },
+
+    /// The response couldn't be deserialized into the expected model.
+    Decode { url: String, message: String },
+}
+
+impl ApiError {
+    /// Classifies a response that didn't succeed.
+    pub fn from_response(url: &str, response: &Response) -> Self {
+        let url = url.to_string();
+        let message = response.as_str().unwrap_or_default().trim().to_string();
+
+        if let Some(retry_after) = rate_limit_delay(response) {
+            return ApiError::RateLimited { url, retry_after };
+        }
+
+        match response.status_code {
+            401 => ApiError::Unauthorized { url, message },
+            403 => ApiError::Forbidden { url, message },
+            404 => ApiError::NotFound { url },
+            429 => ApiError::RateLimited {
+                url,
+                retry_after: SECONDARY_RATE_LIMIT_DELAY,
+            },
+            status @ 500..=599 => ApiError::Server {
+                url,
+                status,
+                message,
+            },
+            status => ApiError::Unexpected {
+                url,
+                status,
+                message,
+            },
+        }
+    }
+
+    pub fn network(url: &str, error: impl std::fmt::Display) -> Self {
+        ApiError::Network {
+            url: url.to_string(),
+            message: error.to_string(),
+        }
+    }
+
+    pub fn decode(url: &str, error: impl std::fmt::Display) -> Self {
+        ApiError::Decode {
+            url: url.to_string(),
+            message: error.to_string(),
+        }
+    }
+
+    /// Whether the same request could succeed if it were simply tried again.
+    pub fn is_transient(&self) -> bool {
+        matches!(
+            self,
+            ApiError::Server { .. } | ApiError::Network { .. } | ApiError::RateLimited { .. }
+        )
+    }
+}
+
+impl std::error::Error for ApiError {}
+
+impl std::fmt::Display for ApiError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ApiError::Unauthorized { url, message } => {
+                write!(
+                    f,
+                    "GET: {} was unauthorized, is the token valid? {}",
+                    url, message
+                )
+            }
+            ApiError::Forbidden { url, message } => write!(
+                f,
+                "GET: {} was forbidden, does the token have push access? {}",
+                url, message
+            ),
+            ApiError::NotFound { url } => write!(f, "GET: {} was not found", url),
+            ApiError::RateLimited { url, retry_after } => write!(
+                f,
+                "GET: {} was rate limited, retry after {}s",
+                url,
+                retry_after.as_secs()
+            ),
+            ApiError::Server {
+                url,
+                status,
+                message,
+            } => write!(
+                f,
+                "GET: {} failed with server error {}, {}",
+                url, status, message
+            ),
+            ApiError::Unexpected {
+                url,
+                status,
+                message,
+            } => write!(
+                f,
+                "GET: {} failed with status code {}, {}",
+                url, status, message
+            ),
+            ApiError::Network { url, message } => write!(f, "GET: {} failed, {}", url, message),
+            ApiError::Decode { url, message } => {
+                write!(
+                    f,
+                    "GET: {} returned an unexpected response, {}",
+                    url, message
+                )
+            }
+        }
+    }
+}
+
+pub type ApiResult<T> = Result<T, ApiError>;
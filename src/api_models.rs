@@ -1,10 +1,12 @@
 pub use serde::{self, Deserialize, Serialize};
-pub use serde_json::{self as sj, json};
+pub use serde_json::{self as sj};
+
+use chrono::{DateTime, Utc};
 
 #[derive(Debug, Deserialize, Serialize, Clone, Default)]
 pub struct ModelRepoClonesHourly {
     pub count: u64,
-    pub timestamp: String,
+    pub timestamp: DateTime<Utc>,
     pub uniques: u64,
 }
 
@@ -18,7 +20,7 @@ pub struct ModelRepoClonesDaily {
 #[derive(Debug, Deserialize, Serialize, Clone, Default)]
 pub struct ModelRepoClonesWeekly {
     pub count: u64,
-    pub timestamp: String,
+    pub timestamp: DateTime<Utc>,
     pub uniques: u64,
 }
 
@@ -51,7 +53,7 @@ pub type ModelContentTrafficBiWeekly = Vec<ModelContentTraffic>;
 #[derive(Debug, Deserialize, Serialize, Clone, Default)]
 pub struct ModelRepoViewsHourly {
     pub count: u64,
-    pub timestamp: String,
+    pub timestamp: DateTime<Utc>,
     pub uniques: u64,
 }
 
@@ -65,7 +67,7 @@ pub struct ModelRepoViewsDaily {
 #[derive(Debug, Deserialize, Serialize, Clone, Default)]
 pub struct ModelRepoViewsWeekly {
     pub count: u64,
-    pub timestamp: String,
+    pub timestamp: DateTime<Utc>,
     pub uniques: u64,
 }
 
@@ -85,7 +87,7 @@ pub struct ModelRepoStargazer {
     pub followers_url: String,
     pub following_url: String,
     pub gists_url: String,
-    pub gravatar_id: String,
+    pub gravatar_id: Option<String>,
     pub html_url: String,
     pub id: u64,
     pub login: String,
@@ -93,10 +95,10 @@ pub struct ModelRepoStargazer {
     pub organizations_url: String,
     pub received_events_url: String,
     pub repos_url: String,
-    pub request_watchers: String,
     pub r#type: String,
     pub site_admin: bool,
     pub starred_url: String,
+    pub subscriptions_url: String,
     pub url: String,
 }
 
@@ -109,7 +111,7 @@ pub struct ModelRepoWatcher {
     pub followers_url: String,
     pub following_url: String,
     pub gists_url: String,
-    pub gravatar_id: String,
+    pub gravatar_id: Option<String>,
     pub html_url: String,
     pub id: u64,
     pub login: String,
@@ -126,106 +128,21 @@ pub struct ModelRepoWatcher {
 
 pub type ModelRepoForks = Vec<ModelRepoFork>;
 
+// Only what the report keeps of a fork, so that none of the many other fields
+// the API returns can fail the request by being missing or null.
 #[derive(Debug, Deserialize, Serialize, Clone, Default)]
 pub struct ModelRepoFork {
-  pub allow_forking: bool,
-  pub archived: bool,
-  pub archive_url: String,
-  pub assignees_url: String,
-  pub blobs_url: String,
-  pub branches_url: String,
-  pub clone_url: String,
-  pub collaborators_url: String,
-  pub comments_url: String,
-  pub commits_url: String,
-  pub compare_url: String,
-  pub contents_url: String,
-  pub contributors_url: String,
-  pub created_at: String,
-  pub default_branch: String,
-  pub deployments_url: String,
-  pub description: String,
-  pub disabled: bool,
-  pub downloads_url: String,
-  pub events_url: String,
-  pub fork: bool,
-  pub forks_count: u64,
-  pub forks: u64,
-  pub forks_url: String,
-  pub full_name: String,
-  pub git_commits_url: String,
-  pub git_refs_url: String,
-  pub git_tags_url: String,
-  pub git_url: String,
-  pub has_discussions: bool,
-  pub has_downloads: bool,
-  pub has_issues: bool,
-  pub has_pages: bool,
-  pub has_projects: bool,
-  pub has_wiki: bool,
-  pub homepage: String,
-  pub hooks_url: String,
-  pub html_url: String,
-  pub id: u64,
-  pub issue_comment_url: String,
-  pub issue_events_url: String,
-  pub issues_url: String,
-  pub is_template: bool,
-  pub keys_url: String,
-  pub labels_url: String,
-  pub language: Option<String>,
-  pub languages_url: String,
-  pub license: ModelLicense,
-  pub merges_url: String,
-  pub milestones_url: String,
-  pub mirror_url: Option<String>,
-  pub name: String,
-  pub node_id: String,
-  pub notifications_url: String,
-  pub open_issues_count: u64,
-  pub open_issues: u64,
-  pub owner: OwnerModel,
-  pub permissions: ModelPermissions,
-  pub private: bool,
-  pub pulls_url: String,
-  pub pushed_at: String,
-  pub releases_url: String,
-  pub size: u64,
-  pub ssh_url: String,
-  pub stargazers_count: u64,
-  pub stargazers_url: String,
-  pub statuses_url: String,
-  pub subscribers_url: String,
-  pub subscription_url: String,
-  pub svn_url: String,
-  pub tags_url: String,
-  pub teams_url: String,
-  pub topics: Vec<String>,
-  pub trees_url: String,
-  pub updated_at: String,
-  pub url: String,
-  pub visibility: String,
-  pub watchers_count: u64,
-  pub watchers: u64,
-  pub web_commit_signoff_required: bool,
-}
-
-#[derive(Debug, Deserialize, Serialize, Clone, Default)]
-pub struct ModelLicense {
-    key: String,
-    name: String,
-    node_id: String,
-    spdx_id: String,
-    url: String,
+    pub full_name: String,
+    pub created_at: DateTime<Utc>,
 }
 
 #[derive(Debug, Deserialize, Serialize, Clone, Default)]
 pub struct ModelPermissions {
-    admin: bool,
-    maintain: bool,
-    pull: bool,
-    push: bool,
-    triage: bool,
+    pub admin: bool,
+    pub maintain: bool,
+    pub pull: bool,
+    pub push: bool,
+    pub triage: bool,
 }
 
 #[derive(Debug, Deserialize, Serialize, Clone, Default)]
@@ -235,10 +152,10 @@ pub struct OwnerModel {
     followers_url: String,
     following_url: String,
     gists_url: String,
-    gravatar_id: String,
+    gravatar_id: Option<String>,
     html_url: String,
     id: u64,
-    login: String,
+    pub login: String,
     node_id: String,
     organizations_url: String,
     received_events_url: String,
@@ -249,3 +166,40 @@ pub struct OwnerModel {
     subscriptions_url: String,
     url: String,
 }
+
+pub type ModelRepositories = Vec<ModelRepository>;
+
+// The repository objects returned by /user/repos and /orgs/{org}/repos. The
+// permissions are only present when the request was authenticated.
+#[derive(Debug, Deserialize, Serialize, Clone, Default)]
+pub struct ModelRepository {
+    pub archived: bool,
+    pub created_at: Option<String>,
+    pub default_branch: Option<String>,
+    pub description: Option<String>,
+    pub disabled: bool,
+    pub fork: bool,
+    pub forks_count: u64,
+    pub full_name: String,
+    pub html_url: String,
+    pub id: u64,
+    pub name: String,
+    pub node_id: String,
+    pub owner: OwnerModel,
+    pub permissions: Option<ModelPermissions>,
+    pub private: bool,
+    pub pushed_at: Option<String>,
+    pub stargazers_count: u64,
+    pub updated_at: Option<String>,
+    pub url: String,
+    pub visibility: Option<String>,
+    pub watchers_count: u64,
+}
+
+// Returned by /app/installations/{installation_id}/access_tokens, the token
+// expires after an hour.
+#[derive(Debug, Deserialize, Serialize, Clone, Default)]
+pub struct ModelInstallationToken {
+    pub token: String,
+    pub expires_at: String,
+}
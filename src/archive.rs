@@ -0,0 +1,355 @@
+use chrono::{DateTime, Utc};
+use log::{info, warn};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::io::Write;
+use std::path::Path;
+
+use crate::api::*;
+use crate::api_models::*;
+use crate::config::*;
+use crate::discovery::*;
+use crate::report::*;
+use crate::storage::*;
+
+use anyhow as ah;
+
+/// The raw data of a single request, as it was before being merged.
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub enum Snapshot {
+    /// Everything a collection cycle requests.
+    Full(Box<ApiDataReport>),
+
+    /// The referrer and popular content windows polled by the midnight sampler.
+    Popular {
+        referrals: ModelReferrerals,
+        content_visits: ModelContentTrafficBiWeekly,
+    },
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct ArchiveEntry {
+    pub fetched_at: DateTime<Utc>,
+
+    #[serde(flatten)]
+    pub snapshot: Snapshot,
+}
+
+/// An append-only log of every snapshot merged into a report, one JSON entry
+/// per line, so that the report can be rebuilt from scratch when the merge
+/// logic changes or the report is lost. Entries are never rewritten, and a
+/// snapshot is only merged once it has been appended.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Archive {
+    pub path: String,
+}
+
+impl Archive {
+    /// The archive kept alongside a report, ./owner-repo.json being archived
+    /// at ./owner-repo.archive.jsonl.
+    pub fn for_report(report_path: &str) -> Self {
+        let stem = report_path.strip_suffix(".json").unwrap_or(report_path);
+
+        Archive {
+            path: format!("{}.archive.jsonl", stem),
+        }
+    }
+
+    pub fn exists(&self) -> bool {
+        Path::new(&self.path).exists()
+    }
+
+    /// Appends a snapshot, and waits for it to reach the disk.
+    pub fn append(&self, fetched_at: DateTime<Utc>, snapshot: Snapshot) -> ah::Result<()> {
+        let entry = ArchiveEntry {
+            fetched_at,
+            snapshot,
+        };
+
+        let mut line = serde_json::to_vec(&entry)?;
+        line.push(b'\n');
+
+        if let Some(parent) = Path::new(&self.path).parent() {
+            std::fs::create_dir_all(parent)?;
+        }
+
+        let mut file = std::fs::OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&self.path)
+            .map_err(|e| ah::anyhow!("Failed to open the archive {}: {}", self.path, e))?;
+
+        file.write_all(&line)?;
+        Ok(file.sync_data()?)
+    }
+
+    /// Every entry, in the order they were appended. A last entry that was cut
+    /// short, e.g. by a crash halfway through appending it, is skipped.
+    pub fn entries(&self) -> ah::Result<Vec<ArchiveEntry>> {
+        let data = std::fs::read_to_string(&self.path)
+            .map_err(|e| ah::anyhow!("Failed to read the archive {}: {}", self.path, e))?;
+
+        let complete = data.ends_with('\n');
+        let lines: Vec<&str> = data.lines().collect();
+        let mut entries = Vec::with_capacity(lines.len());
+
+        for (index, line) in lines.iter().enumerate() {
+            if line.trim().is_empty() {
+                continue;
+            }
+
+            match serde_json::from_str(line) {
+                Ok(entry) => entries.push(entry),
+                Err(_) if !complete && index + 1 == lines.len() => {
+                    warn!("Skipping the truncated last entry of {}", self.path);
+                }
+                Err(e) => ah::bail!(
+                    "Invalid entry on line {} of {}: {}",
+                    index + 1,
+                    self.path,
+                    e
+                ),
+            }
+        }
+
+        Ok(entries)
+    }
+
+    /// Rebuilds the report by merging every entry into it again, as of when
+    /// it was fetched. Samples taken before the first full snapshot are
+    /// skipped, as the watcher wouldn't have had a report to merge them into.
+    pub fn rebuild(&self) -> ah::Result<Option<RepositoryReport>> {
+        let mut report: Option<RepositoryReport> = None;
+
+        for entry in self.entries()? {
+            match (&mut report, &entry.snapshot) {
+                (None, Snapshot::Full(api_data)) => {
+                    report = Some(RepositoryReport::new(api_data, entry.fetched_at)?);
+                }
+                (Some(report), Snapshot::Full(api_data)) => {
+                    report.update(api_data, entry.fetched_at)?;
+                }
+                (
+                    Some(report),
+                    Snapshot::Popular {
+                        referrals,
+                        content_visits,
+                    },
+                ) => {
+                    report.update_popular(referrals, content_visits, entry.fetched_at)?;
+                }
+                (None, Snapshot::Popular { .. }) => {}
+            }
+        }
+
+        Ok(report)
+    }
+}
+
+/// The earliest day, hour or week the report has traffic for that the rebuilt
+/// one doesn't, if any, such as anything from before the archive was enabled.
+fn lost_history(report: &RepositoryReport, rebuilt: &RepositoryReport) -> Option<DateTime<Utc>> {
+    type Events = BTreeMap<DateTime<Utc>, QuantifiableEvents>;
+
+    let lost = |events: &Events, rebuilt: Option<&Events>| {
+        events
+            .keys()
+            .find(|timestamp| !rebuilt.is_some_and(|rebuilt| rebuilt.contains_key(timestamp)))
+            .copied()
+    };
+
+    let lost_timelines = |timelines: &BTreeMap<String, Events>,
+                          rebuilt: &BTreeMap<String, Events>| {
+        timelines
+            .iter()
+            .filter_map(|(key, timeline)| lost(timeline, rebuilt.get(key)))
+            .min()
+    };
+
+    [
+        lost(&report.hourly_views, Some(&rebuilt.hourly_views)),
+        lost(&report.hourly_clones, Some(&rebuilt.hourly_clones)),
+        lost(&report.weekly_views, Some(&rebuilt.weekly_views)),
+        lost(&report.weekly_clones, Some(&rebuilt.weekly_clones)),
+        lost_timelines(&report.weekly_referrals, &rebuilt.weekly_referrals),
+        lost_timelines(
+            &report.weekly_content_visits,
+            &rebuilt.weekly_content_visits,
+        ),
+    ]
+    .into_iter()
+    .flatten()
+    .min()
+}
+
+/// The rebuild command: rebuilds the report of every listed and discovered
+/// repository that has an archive, keeping the previous report as a backup.
+/// A report with history that the archive doesn't have, such as from before it
+/// was enabled, is left as it is.
+pub fn run(config: &Config) -> ah::Result<()> {
+    let backend = StorageBackend::new(config)?;
+    let mut targets = config.targets();
+
+    match discover_targets(config) {
+        Ok(discovered) => targets.extend(discovered),
+        Err(error) => warn!("{}", error),
+    }
+
+    for target in &targets {
+        let archive = Archive::for_report(&target.report_path);
+
+        if !archive.exists() {
+            warn!(
+                "No archive to rebuild {} from at {}",
+                target.name(),
+                archive.path
+            );
+            continue;
+        }
+
+        let Some(report) = archive.rebuild()? else {
+            warn!("The archive {} has no complete snapshot yet", archive.path);
+            continue;
+        };
+
+        let mut storage = backend.open(target);
+        storage.lock()?;
+
+        if let Some(lost) = storage
+            .load()?
+            .and_then(|current| lost_history(&current, &report))
+        {
+            warn!(
+                "Not rebuilding {}: its report has history from {} on that {} doesn't, which the rebuild would lose. Move the report aside to rebuild it anyway",
+                target.name(),
+                lost,
+                archive.path
+            );
+            continue;
+        }
+
+        // Only a report in its own file can be backed up. In the database, the
+        // archive it was rebuilt from is its backup.
+        if let Some(backup) = storage.backup()? {
+            info!(
+                "Backed up the previous report of {} to {}",
+                target.name(),
+                backup
+            );
+        }
+
+        storage.upsert_snapshot(&report)?;
+        info!(
+            "Rebuilt the report of {} from {}",
+            target.name(),
+            archive.path
+        );
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::timecalc::*;
+    use chrono::Duration;
+
+    fn views(timestamp: &str, count: u64) -> ApiDataReport {
+        let mut api_data = ApiDataReport::default();
+
+        api_data.daily_views_model.views.push(ModelRepoViewsHourly {
+            timestamp: parse_time(timestamp).unwrap(),
+            count,
+            uniques: 1,
+        });
+
+        api_data
+    }
+
+    #[test]
+    fn test_rebuild_replays_the_archive() {
+        let report_path = std::env::temp_dir().join(format!("report-{}.json", std::process::id()));
+        let archive = Archive::for_report(report_path.to_str().unwrap());
+        assert!(archive.path.ends_with(".archive.jsonl"));
+        let _ = std::fs::remove_file(&archive.path);
+
+        let fetched_at = Utc::now() - Duration::days(2);
+        let referrals = vec![ModelReferrer {
+            referrer: "example.com".into(),
+            count: 3,
+            uniques: 2,
+        }];
+
+        archive
+            .append(
+                fetched_at,
+                Snapshot::Popular {
+                    referrals: referrals.clone(),
+                    content_visits: vec![],
+                },
+            )
+            .unwrap();
+
+        archive
+            .append(
+                fetched_at,
+                Snapshot::Full(Box::new(views("2024-01-01T00:00:00Z", 5))),
+            )
+            .unwrap();
+
+        archive
+            .append(
+                fetched_at + Duration::hours(1),
+                Snapshot::Full(Box::new(views("2024-01-01T01:00:00Z", 7))),
+            )
+            .unwrap();
+
+        archive
+            .append(
+                fetched_at + Duration::hours(2),
+                Snapshot::Popular {
+                    referrals,
+                    content_visits: vec![],
+                },
+            )
+            .unwrap();
+
+        // A crash halfway through an append leaves a partial line behind.
+        let mut file = std::fs::OpenOptions::new()
+            .append(true)
+            .open(&archive.path)
+            .unwrap();
+        file.write_all(br#"{"fetched_at":"2024-"#).unwrap();
+
+        assert_eq!(archive.entries().unwrap().len(), 4);
+
+        let report = archive.rebuild().unwrap().unwrap();
+        std::fs::remove_file(&archive.path).unwrap();
+
+        assert_eq!(
+            report.hourly_views[&parse_time("2024-01-01T00:00:00Z").unwrap()].amount,
+            5
+        );
+        assert_eq!(
+            report.hourly_views[&parse_time("2024-01-01T01:00:00Z").unwrap()].amount,
+            7
+        );
+        assert_eq!(report.biweekly_referrals["example.com"].amount, 3);
+
+        // A report from before the archive has history it can't rebuild.
+        let mut current = report.clone();
+        assert!(lost_history(&current, &report).is_none());
+
+        let before = parse_time("2023-12-01").unwrap();
+        current.hourly_views.insert(before, Default::default());
+        current
+            .weekly_referrals
+            .entry("example.com".into())
+            .or_default()
+            .insert(parse_time("2023-12-02").unwrap(), Default::default());
+
+        assert_eq!(lost_history(&current, &report), Some(before));
+    }
+}
@@ -0,0 +1,322 @@
+use log::{debug, warn};
+use serde::Deserialize;
+use std::collections::HashMap;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::process::{Command, Stdio};
+use std::sync::{LazyLock, Mutex};
+use std::time::{Duration, Instant};
+
+use crate::api::*;
+use crate::config::*;
+use crate::github_app::*;
+use crate::vault::*;
+
+use anyhow as ah;
+
+/// The environment variables a token for github.com is taken from, in order
+/// of precedence, the same as the gh CLI.
+pub const TOKEN_VARIABLES: [&str; 2] = ["GH_TOKEN", "GITHUB_TOKEN"];
+
+/// The environment variables a token for any other host is taken from, in
+/// order of precedence, the same as the gh CLI.
+pub const ENTERPRISE_TOKEN_VARIABLES: [&str; 2] =
+    ["GH_ENTERPRISE_TOKEN", "GITHUB_ENTERPRISE_TOKEN"];
+
+/// How long an ambient token is reused before it's looked up again. Looking it
+/// up can mean running gh or git, which isn't worth doing for every repository
+/// on every pass of the scheduler.
+const AMBIENT_TOKEN_LIFETIME: Duration = Duration::from_secs(5 * 60);
+
+static AMBIENT_TOKENS: LazyLock<Mutex<HashMap<String, (Instant, AuthToken)>>> =
+    LazyLock::new(|| Mutex::new(HashMap::new()));
+
+/// Where the token of a repository comes from. Resolved at the start of every
+/// collection cycle, so that tokens can be rotated without a restart, except
+/// for tokens in the vault, which is only unlocked once at startup.
+/// Ambient tokens are reused for AMBIENT_TOKEN_LIFETIME.
+#[derive(Debug, Clone)]
+pub enum TokenSource {
+    Literal(AuthToken),
+    Vault(String),
+    File(String),
+
+    /// An installation token of a GitHub App, which is cached and only issued
+    /// again shortly before it expires.
+    App(AppConfig),
+
+    /// Whatever is set up on the machine already, when no token is configured
+    /// explicitly. See resolve_ambient_token for the order they're tried in.
+    Ambient,
+}
+
+impl TokenSource {
+    pub fn resolve(&self, host: &ApiHost, policy: &RequestPolicy) -> ah::Result<AuthToken> {
+        match self {
+            TokenSource::Literal(token) => Ok(token.clone()),
+            TokenSource::Vault(name) => vault_token(name),
+            TokenSource::File(path) => read_token(path)
+                .map_err(|e| ah::anyhow!("Failed to read the token file {}: {}", path, e)),
+            TokenSource::App(app) => app.installation_token(host, policy).map_err(|e| {
+                ah::anyhow!(
+                    "Failed to get an installation token for app {}, installation {}: {}",
+                    app.app_id,
+                    app.installation_id,
+                    e
+                )
+            }),
+            TokenSource::Ambient => ambient_token(host),
+        }
+    }
+}
+
+/// The ambient token for the host, looked up again once the last one found is
+/// older than AMBIENT_TOKEN_LIFETIME.
+fn ambient_token(host: &ApiHost) -> ah::Result<AuthToken> {
+    let cached = AMBIENT_TOKENS
+        .lock()
+        .ok()
+        .and_then(|tokens| tokens.get(&host.api_url).cloned());
+
+    if let Some((found_at, token)) = cached {
+        if found_at.elapsed() < AMBIENT_TOKEN_LIFETIME {
+            return Ok(token);
+        }
+    }
+
+    let token = resolve_ambient_token(host)?;
+
+    if let Ok(mut tokens) = AMBIENT_TOKENS.lock() {
+        tokens.insert(host.api_url.clone(), (Instant::now(), token.clone()));
+    }
+
+    Ok(token)
+}
+
+/// Looks for a token in, in order:
+///
+/// 1. The default token file, ./auth.secret, if it exists, for github.com.
+/// 2. The GH_TOKEN and GITHUB_TOKEN environment variables for github.com, or
+///    GH_ENTERPRISE_TOKEN and GITHUB_ENTERPRISE_TOKEN for any other host.
+/// 3. The hosts file of the gh CLI, if it stores the token there rather than
+///    in the system keyring.
+/// 4. The git credential helper, as used for HTTPS remotes.
+pub fn resolve_ambient_token(host: &ApiHost) -> ah::Result<AuthToken> {
+    let variable = variable_token(host, |name| std::env::var(name).ok());
+    let github = is_public_github(host);
+
+    if github && Path::new(AUTH_FILE).exists() {
+        if let Some((variable, _)) = &variable {
+            warn!(
+                "Both {} and ${} are set, using {}",
+                AUTH_FILE, variable, AUTH_FILE
+            );
+        } else {
+            debug!("Using the token from {}", AUTH_FILE);
+        }
+
+        return read_token(AUTH_FILE)
+            .map_err(|e| ah::anyhow!("Failed to read the token file {}: {}", AUTH_FILE, e));
+    }
+
+    if let Some((variable, token)) = variable {
+        debug!("Using the token from ${}", variable);
+        return Ok(token);
+    }
+
+    if let Some(token) = gh_hosts_token(&host.name) {
+        debug!("Using the token of the gh CLI for {}", host.name);
+        return Ok(token);
+    }
+
+    if let Some(token) = credential_helper_token(&host.name) {
+        debug!(
+            "Using the token from the git credential helper for {}",
+            host.name
+        );
+        return Ok(token);
+    }
+
+    let variables = token_variables(host);
+
+    ah::bail!(
+        "No token found: none is configured, {}${} and ${} are unset, and neither gh nor git have one for {}",
+        match github {
+            true => format!("there is no {}, ", AUTH_FILE),
+            false => String::new(),
+        },
+        variables[0],
+        variables[1],
+        host.name
+    )
+}
+
+/// Whether requests go to github.com itself, the only host that the token in
+/// $GH_TOKEN, $GITHUB_TOKEN or ./auth.secret is ever sent to.
+fn is_public_github(host: &ApiHost) -> bool {
+    host.is_github() && host.api_url == API_BASE
+}
+
+fn token_variables(host: &ApiHost) -> [&'static str; 2] {
+    match is_public_github(host) {
+        true => TOKEN_VARIABLES,
+        false => ENTERPRISE_TOKEN_VARIABLES,
+    }
+}
+
+/// The token in the first of the environment variables for the host that is
+/// set, along with the variable, given a way to look them up.
+fn variable_token(
+    host: &ApiHost,
+    var: impl Fn(&str) -> Option<String>,
+) -> Option<(&'static str, AuthToken)> {
+    token_variables(host).into_iter().find_map(|variable| {
+        var(variable)
+            .filter(|token| !token.trim().is_empty())
+            .map(|token| (variable, AuthToken(token.trim().to_string())))
+    })
+}
+
+#[derive(Debug, Deserialize)]
+struct GhHost {
+    #[serde(default)]
+    oauth_token: Option<String>,
+}
+
+/// Where gh keeps its configuration: $GH_CONFIG_DIR, $XDG_CONFIG_HOME/gh,
+/// %AppData%/GitHub CLI on Windows, or ~/.config/gh.
+fn gh_config_dir() -> Option<PathBuf> {
+    let var = |name| std::env::var_os(name).filter(|value| !value.is_empty());
+
+    if let Some(dir) = var("GH_CONFIG_DIR") {
+        return Some(dir.into());
+    }
+
+    if let Some(dir) = var("XDG_CONFIG_HOME") {
+        return Some(PathBuf::from(dir).join("gh"));
+    }
+
+    if cfg!(windows) {
+        if let Some(dir) = var("AppData") {
+            return Some(PathBuf::from(dir).join("GitHub CLI"));
+        }
+    }
+
+    var("HOME").map(|home| PathBuf::from(home).join(".config").join("gh"))
+}
+
+fn gh_hosts_token(host: &str) -> Option<AuthToken> {
+    let path = gh_config_dir()?.join("hosts.yml");
+    let hosts = std::fs::read_to_string(path).ok()?;
+
+    parse_gh_hosts(&hosts, host)
+}
+
+fn parse_gh_hosts(hosts: &str, host: &str) -> Option<AuthToken> {
+    let mut hosts: HashMap<String, GhHost> = serde_yaml::from_str(hosts).ok()?;
+
+    hosts
+        .remove(host)?
+        .oauth_token
+        .filter(|token| !token.is_empty())
+        .map(AuthToken)
+}
+
+/// Asks git for the credentials it would use for https://{host}, without ever
+/// prompting for them, as there's nobody to answer a prompt in a service.
+fn credential_helper_token(host: &str) -> Option<AuthToken> {
+    let mut child = Command::new("git")
+        .args(["credential", "fill"])
+        .env("GIT_TERMINAL_PROMPT", "0")
+        .env("GCM_INTERACTIVE", "never")
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::null())
+        .spawn()
+        .ok()?;
+
+    let request = format!("protocol=https\nhost={}\n\n", host);
+    child.stdin.take()?.write_all(request.as_bytes()).ok()?;
+
+    let output = child.wait_with_output().ok()?;
+
+    if !output.status.success() {
+        return None;
+    }
+
+    parse_credential_output(&String::from_utf8_lossy(&output.stdout))
+}
+
+fn parse_credential_output(output: &str) -> Option<AuthToken> {
+    output
+        .lines()
+        .find_map(|line| line.strip_prefix("password="))
+        .filter(|password| !password.is_empty())
+        .map(|password| AuthToken(password.to_string()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_gh_hosts() {
+        let hosts = "\
+github.com:
+    users:
+        octocat:
+            oauth_token: gho_users
+    git_protocol: https
+    oauth_token: gho_current
+    user: octocat
+ghe.example.com:
+    user: octocat
+";
+
+        assert_eq!(
+            parse_gh_hosts(hosts, "github.com").unwrap().0,
+            "gho_current"
+        );
+        assert!(parse_gh_hosts(hosts, "ghe.example.com").is_none());
+        assert!(parse_gh_hosts(hosts, "gitlab.com").is_none());
+    }
+
+    #[test]
+    fn test_variable_token_for_host() {
+        let var = |name: &str| match name {
+            "GH_TOKEN" => Some("ghp_public".to_string()),
+            "GITHUB_ENTERPRISE_TOKEN" => Some("ghp_enterprise\n".to_string()),
+            _ => None,
+        };
+
+        let (variable, token) = variable_token(&ApiHost::default(), var).unwrap();
+        assert_eq!((variable, token.0.as_str()), ("GH_TOKEN", "ghp_public"));
+
+        // The github.com token is never sent anywhere else.
+        let enterprise = ApiHost::new("github.example.com", None);
+        let (variable, token) = variable_token(&enterprise, var).unwrap();
+        assert_eq!(
+            (variable, token.0.as_str()),
+            ("GITHUB_ENTERPRISE_TOKEN", "ghp_enterprise")
+        );
+
+        let proxied = HostConfig {
+            api_url: Some("https://proxy.example.com".into()),
+            api_version: None,
+            user_agent: None,
+        };
+        let proxied = ApiHost::new(GITHUB_HOST, Some(&proxied));
+        assert!(
+            variable_token(&proxied, |name| var(name).filter(|_| name == "GH_TOKEN")).is_none()
+        );
+        assert!(!is_public_github(&proxied));
+        assert!(is_public_github(&ApiHost::default()));
+    }
+
+    #[test]
+    fn test_parse_credential_output() {
+        let output = "protocol=https\nhost=github.com\nusername=octocat\npassword=ghp_secret\n";
+        assert_eq!(parse_credential_output(output).unwrap().0, "ghp_secret");
+        assert!(parse_credential_output("protocol=https\nhost=github.com\n").is_none());
+    }
+}
@@ -0,0 +1,484 @@
+use serde::Deserialize;
+use std::collections::HashMap;
+use std::time::Duration;
+
+use crate::api::*;
+use crate::archive::*;
+use crate::auth::*;
+use crate::crypto::*;
+use crate::github_app::*;
+use crate::logging::*;
+use crate::storage::*;
+use crate::vault::*;
+
+use anyhow as ah;
+
+pub const AUTH_FILE: &str = "./auth.secret";
+pub const DEFAULT_INTERVAL: u64 = 60 * 60;
+pub const DEFAULT_DISCOVERY_INTERVAL: u64 = 24 * 60 * 60;
+pub const DEFAULT_BACKUPS: usize = 3;
+
+/// The configuration file, listing every repository to collect metrics for.
+///
+/// ```json
+/// {
+///   "interval": 3600,
+///   "auth_file": "./auth.secret",
+///   "report_dir": "./reports",
+///   "backups": 3,
+///   "storage": "database",
+///   "database": "./reports/chronicler.sqlite",
+///   "passphrase_file": "./passphrase.secret",
+///   "kdf": { "mem_cost": 19456, "time_cost": 2, "lanes": 1 },
+///   "repositories": [
+///     { "owner": "PsychedelicShayna", "repository": "cursor-locker" },
+///     { "owner": "SomeOrg", "repository": "project", "auth_file": "./org.secret" },
+///     { "owner": "SomeOrg", "repository": "private", "vault_token": "some-org" },
+///     {
+///       "owner": "SomeOrg", "repository": "installed",
+///       "app": { "app_id": 123456, "installation_id": 7890123, "private_key_file": "./app.pem" }
+///     },
+///     { "owner": "SomeOrg", "repository": "other", "report": "./other.json" },
+///     { "owner": "Team", "repository": "internal", "host": "github.example.com", "vault_token": "ghes" }
+///   ],
+///   "user_agent": "chronicler (ops@example.com)",
+///   "hosts": {
+///     "github.example.com": { "api_version": "2022-11-28" }
+///   },
+///   "discover": [
+///     { "exclude": ["*/dotfiles"] },
+///     { "org": "SomeOrg", "auth_file": "./org.secret", "forks": true }
+///   ],
+///   "requests": { "timeout": 60, "retries": 4, "retry_delay": 2, "max_retry_delay": 120 },
+///   "log": { "level": "info", "file": "./chronicler.jsonl" }
+/// }
+/// ```
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Config {
+    /// Seconds between collection cycles.
+    #[serde(default = "default_interval")]
+    pub interval: u64,
+
+    /// The token file used by repositories that don't specify their own. If
+    /// absent, the token is looked for in the environment, see TokenSource.
+    #[serde(default)]
+    pub auth_file: Option<String>,
+
+    /// The GitHub App installation used by repositories that don't specify
+    /// their own credentials, taking precedence over auth_file.
+    #[serde(default)]
+    pub app: Option<AppConfig>,
+
+    /// The token vault that vault_token names are looked up in.
+    #[serde(default = "default_vault_file")]
+    pub vault_file: String,
+
+    /// Where reports are stored, for repositories that don't specify a path.
+    #[serde(default = "default_report_dir")]
+    pub report_dir: String,
+
+    /// How reports are stored, see StorageKind. The database isn't encrypted,
+    /// so it can't be combined with passphrase_file. Defaults to json, unless
+    /// a database is given.
+    #[serde(default)]
+    pub storage: Option<StorageKind>,
+
+    /// How many of the previous versions of each report file to keep, as
+    /// <report>.<timestamp>.bak, when it's replaced by a save.
+    #[serde(default = "default_backups")]
+    pub backups: usize,
+
+    /// The SQLite database that reports are stored in with the database
+    /// storage. (<report_dir>/reports.sqlite)
+    #[serde(default)]
+    pub database: Option<String>,
+
+    /// A file containing the passphrase that reports are encrypted with. Plain
+    /// reports are encrypted the next time they're saved.
+    #[serde(default)]
+    pub passphrase_file: Option<String>,
+
+    /// How keys are derived from the passphrase. Every report records the
+    /// parameters it was encrypted with, so these can be changed at any time.
+    #[serde(default)]
+    pub kdf: KdfParams,
+
+    /// Whether to sample the referrer and popular content rollover at midnight.
+    #[serde(default = "default_sampling")]
+    pub sampling: bool,
+
+    /// Whether to keep every raw snapshot in an archive next to each report,
+    /// from which the report can be rebuilt, see Archive. Archives aren't
+    /// encrypted, so this can't be combined with passphrase_file.
+    #[serde(default)]
+    pub archive: bool,
+
+    #[serde(default)]
+    pub repositories: Vec<RepositoryConfig>,
+
+    /// Sources of repositories that are enrolled automatically.
+    #[serde(default)]
+    pub discover: Vec<DiscoveryConfig>,
+
+    /// Seconds between re-running discovery, to pick up new repositories.
+    #[serde(default = "default_discovery_interval")]
+    pub discovery_interval: u64,
+
+    /// The User-Agent sent to hosts that don't set their own, which GitHub asks
+    /// to identify whoever runs the collector, e.g. by a contact address.
+    #[serde(default)]
+    pub user_agent: Option<String>,
+
+    /// GitHub Enterprise Server instances, or overrides for github.com, by
+    /// host name. Hosts that aren't listed use the defaults, see HostConfig.
+    #[serde(default)]
+    pub hosts: HashMap<String, HostConfig>,
+
+    /// Timeouts, and how persistently to retry failed requests.
+    #[serde(default)]
+    pub requests: RequestPolicy,
+
+    #[serde(default)]
+    pub log: LogConfig,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct RepositoryConfig {
+    pub owner: String,
+    pub repository: String,
+
+    /// Where the report is stored, {report_dir}/{owner}-{repository}.json if absent.
+    #[serde(default)]
+    pub report: Option<String>,
+
+    /// The host the repository is on, github.com if absent.
+    #[serde(default)]
+    pub host: Option<String>,
+
+    /// The token to use for this repository, taking precedence over auth_file.
+    #[serde(default)]
+    pub token: Option<String>,
+
+    /// The name of a token in the vault, taking precedence over auth_file.
+    #[serde(default)]
+    pub vault_token: Option<String>,
+
+    /// The GitHub App installation to authenticate as, taking precedence over
+    /// auth_file.
+    #[serde(default)]
+    pub app: Option<AppConfig>,
+
+    /// The token file to use for this repository instead of the global one.
+    #[serde(default)]
+    pub auth_file: Option<String>,
+}
+
+/// Enrolls every repository that the token has push access to, either those
+/// of the authenticated user (including organizations they're a member of),
+/// or those of a single organization. Patterns are globs matched against the
+/// full "owner/name" of a repository, case insensitively, where * doesn't
+/// match across the slash. A repository has to match at least one include
+/// pattern, if any are given, and none of the exclude patterns.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct DiscoveryConfig {
+    /// The organization to list the repositories of, or the user if absent.
+    #[serde(default)]
+    pub org: Option<String>,
+
+    /// The host to discover repositories on, github.com if absent.
+    #[serde(default)]
+    pub host: Option<String>,
+
+    #[serde(default)]
+    pub include: Vec<String>,
+
+    #[serde(default)]
+    pub exclude: Vec<String>,
+
+    /// Whether to enroll archived repositories, whose traffic can still change.
+    #[serde(default)]
+    pub archived: bool,
+
+    /// Whether to enroll forks.
+    #[serde(default)]
+    pub forks: bool,
+
+    #[serde(default)]
+    pub token: Option<String>,
+
+    #[serde(default)]
+    pub vault_token: Option<String>,
+
+    #[serde(default)]
+    pub app: Option<AppConfig>,
+
+    #[serde(default)]
+    pub auth_file: Option<String>,
+}
+
+/// A repository from the configuration, with all of the defaults applied.
+#[derive(Debug, Clone)]
+pub struct RepositoryTarget {
+    pub owner: String,
+    pub repository: String,
+    pub report_path: String,
+    pub host: ApiHost,
+    pub token: TokenSource,
+    pub requests: RequestPolicy,
+
+    /// Where snapshots are archived, if they are.
+    pub archive: Option<Archive>,
+}
+
+impl RepositoryTarget {
+    /// A client for the host of the repository, resolving the token again, see
+    /// TokenSource.
+    pub fn client(&self) -> ah::Result<Client> {
+        let token = self.token.resolve(&self.host, &self.requests)?;
+        Ok(Client::new(self.host.clone(), token, self.requests.clone()))
+    }
+
+    /// The full name of the repository, prefixed by its host unless that's
+    /// github.com.
+    pub fn name(&self) -> String {
+        match self.host.is_github() {
+            true => format!("{}/{}", self.owner, self.repository),
+            false => format!("{}/{}/{}", self.host.name, self.owner, self.repository),
+        }
+    }
+}
+
+impl Config {
+    pub fn load_json_file(file_path: &str) -> ah::Result<Self> {
+        let file = std::fs::File::open(file_path)
+            .map_err(|e| ah::anyhow!("Failed to open the config file {}: {}", file_path, e))?;
+
+        let config: Config = serde_json::from_reader(file)
+            .map_err(|e| ah::anyhow!("Failed to parse the config file {}: {}", file_path, e))?;
+
+        Ok(config)
+    }
+
+    pub fn encryption(&self) -> ah::Result<Option<Encryption>> {
+        let Some(file) = &self.passphrase_file else {
+            return Ok(None);
+        };
+
+        let passphrase = read_passphrase(file)
+            .map_err(|e| ah::anyhow!("Failed to read the passphrase file {}: {}", file, e))?;
+
+        Ok(Some(Encryption {
+            passphrase,
+            kdf: self.kdf,
+        }))
+    }
+
+    pub fn interval(&self) -> Duration {
+        Duration::from_secs(self.interval)
+    }
+
+    pub fn discovery_interval(&self) -> Duration {
+        Duration::from_secs(self.discovery_interval)
+    }
+
+    /// The explicitly listed repositories. See discovery::discover_targets for
+    /// the ones that are enrolled automatically.
+    pub fn targets(&self) -> Vec<RepositoryTarget> {
+        self.repositories
+            .iter()
+            .map(|repository| {
+                let host = self.host(&repository.host);
+                let report_path = repository.report.clone().unwrap_or_else(|| {
+                    self.default_report_path(&host, &repository.owner, &repository.repository)
+                });
+
+                RepositoryTarget {
+                    owner: repository.owner.clone(),
+                    repository: repository.repository.clone(),
+                    archive: self.archive(&report_path),
+                    report_path,
+                    host,
+                    token: self.token_source(
+                        &repository.token,
+                        &repository.vault_token,
+                        &repository.app,
+                        &repository.auth_file,
+                    ),
+                    requests: self.requests.clone(),
+                }
+            })
+            .collect()
+    }
+
+    /// Reports of repositories on other hosts than github.com are prefixed by
+    /// the host, as the same owner and repository can exist on both.
+    pub fn default_report_path(&self, host: &ApiHost, owner: &str, repository: &str) -> String {
+        let report_dir = self.report_dir.trim_end_matches('/');
+
+        match host.is_github() {
+            true => format!("{}/{}-{}.json", report_dir, owner, repository),
+            false => format!("{}/{}-{}-{}.json", report_dir, host.name, owner, repository),
+        }
+    }
+
+    /// The archive of the report at the given path, if archiving is enabled.
+    pub fn archive(&self, report_path: &str) -> Option<Archive> {
+        self.archive.then(|| Archive::for_report(report_path))
+    }
+
+    /// The host of the given name, github.com if none is given.
+    pub fn host(&self, name: &Option<String>) -> ApiHost {
+        let name = name.as_deref().unwrap_or(GITHUB_HOST);
+        let config = self.hosts.get(name);
+        let mut host = ApiHost::new(name, config);
+
+        if let Some(user_agent) = &self.user_agent {
+            if config
+                .and_then(|config| config.user_agent.as_ref())
+                .is_none()
+            {
+                host.user_agent = user_agent.clone();
+            }
+        }
+
+        host
+    }
+
+    pub fn storage(&self) -> StorageKind {
+        match (self.storage, &self.database) {
+            (Some(kind), _) => kind,
+            (None, Some(_)) => StorageKind::Database,
+            (None, None) => StorageKind::Json,
+        }
+    }
+
+    pub fn database_path(&self) -> String {
+        match &self.database {
+            Some(path) => path.clone(),
+            None => format!("{}/reports.sqlite", self.report_dir.trim_end_matches('/')),
+        }
+    }
+
+    /// The ETag cache is kept with the reports, as it's only valid alongside
+    /// them: an unmodified response has already been merged into a report.
+    pub fn etag_cache_path(&self) -> String {
+        format!("{}/etags.json", self.report_dir.trim_end_matches('/'))
+    }
+
+    /// Where the token of a repository or discovery comes from, given its own
+    /// credentials, falling back on the global app and auth_file.
+    pub fn token_source(
+        &self,
+        token: &Option<String>,
+        vault_token: &Option<String>,
+        app: &Option<AppConfig>,
+        auth_file: &Option<String>,
+    ) -> TokenSource {
+        match (token, vault_token, app, auth_file) {
+            (Some(token), _, _, _) => TokenSource::Literal(AuthToken(token.clone())),
+            (None, Some(name), _, _) => TokenSource::Vault(name.clone()),
+            (None, None, Some(app), _) => TokenSource::App(app.clone()),
+            (None, None, None, Some(file)) => TokenSource::File(file.clone()),
+            (None, None, None, None) => match (&self.app, &self.auth_file) {
+                (Some(app), _) => TokenSource::App(app.clone()),
+                (None, Some(file)) => TokenSource::File(file.clone()),
+                (None, None) => TokenSource::Ambient,
+            },
+        }
+    }
+
+    /// Whether any token is to be taken from the vault, i.e. whether it has to
+    /// be unlocked before collecting.
+    pub fn uses_vault(&self) -> bool {
+        let repositories = self.repositories.iter().map(|r| &r.vault_token);
+        let discoveries = self.discover.iter().map(|d| &d.vault_token);
+
+        repositories.chain(discoveries).any(Option::is_some)
+    }
+
+    /// Checks the configuration once any command line overrides are applied.
+    pub fn validate(&self) -> ah::Result<()> {
+        if self.interval == 0 {
+            ah::bail!("The interval must be at least one second");
+        }
+
+        if self.storage() == StorageKind::Database && self.passphrase_file.is_some() {
+            ah::bail!(
+                "The database isn't encrypted, so the database storage can't be used with passphrase_file"
+            );
+        }
+
+        if self.database.is_some() && self.storage() != StorageKind::Database {
+            ah::bail!("A database is given, but reports aren't stored in the database");
+        }
+
+        if self.archive && self.passphrase_file.is_some() {
+            ah::bail!("Archives aren't encrypted, so archive can't be used with passphrase_file");
+        }
+
+        if self.repositories.is_empty() && self.discover.is_empty() {
+            ah::bail!("No repositories are listed, and none are to be discovered");
+        }
+
+        self.kdf.validate()?;
+
+        for (name, host) in &self.hosts {
+            if let Some(api_url) = &host.api_url {
+                if !api_url.starts_with("https://") && !api_url.starts_with("http://") {
+                    ah::bail!(
+                        "The api_url of {} must be an http(s) URL: {}",
+                        name,
+                        api_url
+                    );
+                }
+            }
+        }
+
+        for discovery in &self.discover {
+            for pattern in discovery.include.iter().chain(&discovery.exclude) {
+                glob::Pattern::new(pattern)
+                    .map_err(|e| ah::anyhow!("Invalid discovery pattern {}: {}", pattern, e))?;
+            }
+        }
+
+        let mut report_paths = std::collections::HashSet::new();
+
+        for target in self.targets() {
+            if !report_paths.insert(target.report_path.clone()) {
+                ah::bail!(
+                    "More than one repository is stored at {}, the reports would overwrite each other",
+                    target.report_path
+                );
+            }
+        }
+
+        Ok(())
+    }
+}
+
+fn default_interval() -> u64 {
+    DEFAULT_INTERVAL
+}
+
+fn default_discovery_interval() -> u64 {
+    DEFAULT_DISCOVERY_INTERVAL
+}
+
+fn default_vault_file() -> String {
+    VAULT_FILE.into()
+}
+
+fn default_report_dir() -> String {
+    ".".into()
+}
+
+fn default_backups() -> usize {
+    DEFAULT_BACKUPS
+}
+
+fn default_sampling() -> bool {
+    true
+}
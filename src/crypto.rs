@@ -1,241 +1,468 @@
 use aes::{
     self,
-    cipher::{
-        block_padding::Pkcs7, generic_array::GenericArray, typenum::U32, BlockDecryptMut,
-        BlockEncryptMut, KeyIvInit,
-    },
+    cipher::{block_padding::Pkcs7, BlockDecryptMut, BlockEncryptMut, KeyIvInit},
     Aes256,
 };
 
 use cbc::{Decryptor, Encryptor};
+use hmac::{Hmac, Mac};
 use rand::{rngs::StdRng, RngCore, SeedableRng};
-use sha2::{Digest, Sha256};
+use serde::Deserialize;
+use sha2::Sha256;
 
 use anyhow as ah;
 use anyhow::{anyhow, bail};
 
-enum Pkcs7Error {
-    Pkcs7DrainedBytesMismatch {
-        drained_bytes: Vec<u8>,
-    },
-    Pkcs7PaddingSumMismatch {
-        data_length: usize,
-        padding_length: usize,
-        padding_sum: usize,
-    },
-    Pkcs7InvalidPaddingLength {
-        data_length: usize,
-        padding_length: usize,
-    },
-}
-
-impl std::error::Error for Pkcs7Error {
-    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
-        None
-    }
+type HmacSha256 = Hmac<Sha256>;
 
-    fn description(&self) -> &str {
-        "description() is deprecated; use Display"
-    }
+typedef!(pub, Passphrase, String);
+
+pub fn read_passphrase(file: &str) -> ah::Result<Passphrase> {
+    let passphrase = std::fs::read_to_string(file)?;
+    let passphrase = passphrase.trim_end_matches(['\r', '\n']);
 
-    fn cause(&self) -> Option<&dyn std::error::Error> {
-        self.source()
+    if passphrase.is_empty() {
+        bail!("The passphrase is empty");
     }
+
+    Ok(Passphrase(passphrase.to_string()))
 }
 
-impl std::fmt::Debug for Pkcs7Error {
-    fn fmt(&self, _f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        // match self {
-        //     // Pkcs7Error::Pkcs7DrainedBytesMismatch => todo!(),
-        //     // Pkcs7Error::Pkcs7PaddingSumMismatch => todo!(),
-        //     // Pkcs7Error::Pkcs7InvalidPaddingLength => todo!(),
-        //     todo!();
-        // }
-        //
-        todo!()
-    }
+fn random_block() -> [u8; 16] {
+    let mut rng = StdRng::from_entropy();
+    let mut buffer: [u8; 16] = [0; 16];
+    rng.fill_bytes(&mut buffer);
+    buffer
 }
-impl std::fmt::Display for Pkcs7Error {
-    fn fmt(&self, _f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        todo!()
-    }
+
+/// Derives a 32 byte key from the data, e.g. a passphrase, with Argon2id. The
+/// salt should be random and unique to whatever the key encrypts.
+pub fn derive_key(data: &[u8], salt: &[u8], params: &KdfParams) -> ah::Result<[u8; 32]> {
+    params.validate()?;
+
+    let config = argon2::Config {
+        variant: argon2::Variant::Argon2id,
+        version: argon2::Version::Version13,
+        mem_cost: params.mem_cost,
+        time_cost: params.time_cost,
+        lanes: params.lanes,
+        hash_length: 32,
+        ..argon2::Config::default()
+    };
+
+    let hash = argon2::hash_raw(data, salt, &config)?;
+
+    hash.try_into().map_err(|hash: Vec<u8>| {
+        anyhow!(
+            "Argon2 returned a {} byte hash instead of a 32 byte key",
+            hash.len()
+        )
+    })
 }
 
-pub fn pkcs7_apply(data_bytes: &mut Vec<u8>, multiple: usize) -> ah::Result<usize> {
-    let data_length = data_bytes.len();
+/// The length of the HMAC-SHA256 tag that follows the ciphertext.
+pub const TAG_LENGTH: usize = 32;
 
-    if multiple == 0 || data_length == 0 {
-        return Ok(0);
-    }
+/// Splits a key into independent keys for encryption and authentication, so
+/// that the same key is never used for both.
+fn split_key(key: &[u8; 32]) -> ([u8; 32], [u8; 32]) {
+    let subkey = |label: &[u8]| -> [u8; 32] {
+        let mut mac =
+            <HmacSha256 as Mac>::new_from_slice(key).expect("HMAC accepts any key length");
+        mac.update(label);
+        mac.finalize().into_bytes().into()
+    };
 
-    let length_remainder = data_length % multiple;
+    (subkey(b"encryption"), subkey(b"authentication"))
+}
 
-    if length_remainder == 0 {
-        return Ok(0);
+/// The tag of the IV, the associated data, and the ciphertext.
+fn authenticate(
+    mac_key: &[u8; 32],
+    iv: &[u8; 16],
+    associated_data: &[u8],
+    ciphertext: &[u8],
+) -> HmacSha256 {
+    let mut mac =
+        <HmacSha256 as Mac>::new_from_slice(mac_key).expect("HMAC accepts any key length");
+
+    mac.update(iv);
+    mac.update(associated_data);
+    mac.update(ciphertext);
+    mac
+}
+
+/// Encrypts with AES-256-CBC, then authenticates the IV, the associated data,
+/// and the ciphertext with HMAC-SHA256, returning the ciphertext followed by
+/// the tag.
+pub fn encrypt(
+    plaintext: &[u8],
+    key: &[u8; 32],
+    iv: &[u8; 16],
+    associated_data: &[u8],
+) -> ah::Result<Vec<u8>> {
+    let (encryption_key, mac_key) = split_key(key);
+    let encryptor = Encryptor::<Aes256>::new(&encryption_key.into(), iv.into());
+
+    let mut data = encryptor.encrypt_padded_vec_mut::<Pkcs7>(plaintext);
+    let tag = authenticate(&mac_key, iv, associated_data, &data).finalize();
+
+    data.extend_from_slice(&tag.into_bytes());
+    Ok(data)
+}
+
+/// Verifies the tag of data produced by encrypt before decrypting it, so that
+/// nothing tampered with, or encrypted with another key, is ever decrypted.
+pub fn decrypt(
+    data: &[u8],
+    key: &[u8; 32],
+    iv: &[u8; 16],
+    associated_data: &[u8],
+) -> ah::Result<Vec<u8>> {
+    if data.len() < TAG_LENGTH {
+        bail!(
+            "The ciphertext is truncated, {} bytes is shorter than the tag alone",
+            data.len()
+        );
     }
 
-    let distance_to_multiple = multiple - length_remainder;
-    let padding_length = data_length + distance_to_multiple;
+    let (ciphertext, tag) = data.split_at(data.len() - TAG_LENGTH);
+    let (encryption_key, mac_key) = split_key(key);
 
-    data_bytes.resize(padding_length, distance_to_multiple as u8);
+    authenticate(&mac_key, iv, associated_data, ciphertext)
+        .verify_slice(tag)
+        .map_err(|_| anyhow!("Authentication failed, the key is wrong or the data was modified"))?;
 
-    Ok(distance_to_multiple)
+    Decryptor::<Aes256>::new(&encryption_key.into(), iv.into())
+        .decrypt_padded_vec_mut::<Pkcs7>(ciphertext)
+        .map_err(|e| anyhow!("Failed to decrypt: {}", e))
 }
 
-pub fn pkcs7_strip(data_bytes: &mut Vec<u8>) -> ah::Result<usize> {
-    let mut padding_length: usize = 0;
-    let data_length = data_bytes.len();
+/// Marks the start of an encrypted file, to tell it apart from plain JSON.
+pub const MAGIC: &[u8; 8] = b"GHCHRON\0";
+
+/// The version of the encrypted file format, bumped whenever the header or the
+/// construction changes, so that files written by other versions are rejected
+/// with an explanation instead of failing to decrypt.
+pub const FORMAT_VERSION: u8 = 1;
 
-    if let Some(&last) = data_bytes.last() {
-        padding_length = last as usize;
+/// The size of the header that precedes the ciphertext of an encrypted file:
+/// the magic, the format version, the KDF parameters, the salt, and the IV.
+pub const HEADER_LENGTH: usize = MAGIC.len() + 1 + 3 * 4 + 16 + 16;
 
-        let original_data_length = data_length - padding_length;
-        let _original_data_modulo = original_data_length % padding_length;
+/// The Argon2 parameters the key of a file was derived with, which are stored
+/// in its header so that the defaults can change without breaking old files.
+/// The defaults follow the OWASP recommendation for Argon2id.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(deny_unknown_fields, default)]
+pub struct KdfParams {
+    /// Memory in KiB.
+    pub mem_cost: u32,
 
-        if padding_length == 0 || padding_length >= data_length {
-            bail!(Pkcs7Error::Pkcs7InvalidPaddingLength {
-                data_length,
-                padding_length,
-            });
+    /// Passes over the memory.
+    pub time_cost: u32,
+
+    /// Degree of parallelism.
+    pub lanes: u32,
+}
+
+impl Default for KdfParams {
+    fn default() -> Self {
+        KdfParams {
+            mem_cost: 19 * 1024,
+            time_cost: 2,
+            lanes: 1,
         }
+    }
+}
 
-        let padding_start = data_length - padding_length;
-
-        let padding_sum = (padding_start..data_length).fold(0, |acc, i| {
-            if usize::from(data_bytes[i]) == padding_length {
-                acc + 1
-            } else {
-                acc
-            }
-        });
-
-        if padding_sum != padding_length {
-            bail!(Pkcs7Error::Pkcs7PaddingSumMismatch {
-                data_length,
-                padding_length,
-                padding_sum
-            });
+/// The most memory, in KiB, that a key may be derived with. The parameters
+/// are read from the header before the tag can be checked, so anything above
+/// this is refused rather than allocated on the word of an unauthenticated
+/// file.
+pub const MAX_MEM_COST: u32 = 1024 * 1024;
+
+impl KdfParams {
+    /// Rejects parameters Argon2 doesn't accept, as well as ones so costly that
+    /// they're more likely to come from a corrupted header than from a user.
+    pub fn validate(&self) -> ah::Result<()> {
+        if !(1..=64).contains(&self.lanes) {
+            bail!("Argon2 lanes must be between 1 and 64, not {}", self.lanes);
         }
 
-        let mut drained = data_bytes.drain(padding_start..data_length);
+        if !(1..=64).contains(&self.time_cost) {
+            bail!(
+                "Argon2 time cost must be between 1 and 64, not {}",
+                self.time_cost
+            );
+        }
+
+        let (min_memory, max_memory) = (8 * self.lanes, MAX_MEM_COST);
 
-        if drained.len() != padding_length || drained.any(|b| b != last) {
-            bail!(Pkcs7Error::Pkcs7DrainedBytesMismatch {
-                drained_bytes: drained.collect()
-            });
+        if !(min_memory..=max_memory).contains(&self.mem_cost) {
+            bail!(
+                "Argon2 memory cost must be between {} and {} KiB, not {}",
+                min_memory,
+                max_memory,
+                self.mem_cost
+            );
         }
+
+        Ok(())
     }
+}
 
-    Ok(padding_length)
+/// Everything needed to encrypt reports: the passphrase, and the parameters
+/// new keys are derived from it with.
+#[derive(Debug, Clone)]
+pub struct Encryption {
+    pub passphrase: Passphrase,
+    pub kdf: KdfParams,
 }
 
-fn random_block() -> [u8; 16] {
-    let mut rng = StdRng::from_entropy();
-    let mut buffer: [u8; 16] = [0; 16];
-    rng.fill_bytes(&mut buffer);
-    buffer
+/// The header of an encrypted file, everything needed to decrypt it besides
+/// the passphrase. All integers are little endian.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Header {
+    pub version: u8,
+    pub kdf: KdfParams,
+    pub salt: [u8; 16],
+    pub iv: [u8; 16],
 }
 
-pub fn derive_key(data: &Vec<u8>) -> ah::Result<[u8; 32]> {
-    let config = argon2::Config::default();
-    let mut buffer: [u8; 32] = [0u8; 32];
+impl Header {
+    /// A header for a new file, with a fresh salt and IV.
+    pub fn new(kdf: KdfParams) -> Self {
+        Header {
+            version: FORMAT_VERSION,
+            kdf,
+            salt: random_block(),
+            iv: random_block(),
+        }
+    }
 
-    let hash = argon2::hash_raw(data.as_slice(), data.as_slice(), &config)?;
+    pub fn derive_key(&self, passphrase: &str) -> ah::Result<[u8; 32]> {
+        derive_key(passphrase.as_bytes(), &self.salt, &self.kdf)
+    }
 
-    if hash.len() <= buffer.len() {
-        for (i, e) in hash.iter().enumerate() {
-            buffer[i] = *e;
-        }
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut bytes = Vec::with_capacity(HEADER_LENGTH);
+        bytes.extend_from_slice(MAGIC);
+        bytes.push(self.version);
+        bytes.extend_from_slice(&self.kdf.mem_cost.to_le_bytes());
+        bytes.extend_from_slice(&self.kdf.time_cost.to_le_bytes());
+        bytes.extend_from_slice(&self.kdf.lanes.to_le_bytes());
+        bytes.extend_from_slice(&self.salt);
+        bytes.extend_from_slice(&self.iv);
+        bytes
     }
 
-    Ok(buffer)
-}
+    pub fn from_bytes(bytes: &[u8]) -> ah::Result<Self> {
+        if !is_encrypted(bytes) {
+            bail!("The data isn't encrypted, or the header is missing");
+        }
 
-pub fn get_sha256_digest(data: &Vec<u8>) -> Vec<u8> {
-    let mut hasher = Sha256::new();
-    hasher.update(data.as_slice());
-    let hash: GenericArray<u8, U32> = hasher.finalize();
-    hash.to_vec()
-}
+        if bytes.len() < HEADER_LENGTH {
+            bail!(
+                "The header is truncated, {} bytes instead of {}",
+                bytes.len(),
+                HEADER_LENGTH
+            );
+        }
+
+        let version = bytes[MAGIC.len()];
 
-pub fn encrypt(plaintext: &Vec<u8>, key: &[u8; 32]) -> ah::Result<Vec<u8>> {
-    let mut in_data: Vec<u8> = random_block().to_vec();
-    in_data.append(&mut get_sha256_digest(plaintext));
-    in_data.append(&mut plaintext.clone());
+        if version != FORMAT_VERSION {
+            bail!(
+                "Unsupported format version {}, expected {}",
+                version,
+                FORMAT_VERSION
+            );
+        }
 
-    let iv: [u8; 16] = random_block();
-    let encryptor = Encryptor::<Aes256>::new(key.into(), &iv.into());
+        let mut fields = bytes[MAGIC.len() + 1..HEADER_LENGTH].chunks_exact(4);
 
-    let in_length = in_data.len();
+        let mut next_u32 = || -> u32 {
+            let chunk = fields.next().unwrap_or_default();
+            u32::from_le_bytes(chunk.try_into().unwrap_or_default())
+        };
 
-    let mut cipher_buf = in_data.clone();
-    cipher_buf.resize(cipher_buf.len() + 16, 0u8);
+        let kdf = KdfParams {
+            mem_cost: next_u32(),
+            time_cost: next_u32(),
+            lanes: next_u32(),
+        };
 
-    let ciphertext = encryptor.encrypt_padded_mut::<Pkcs7>(cipher_buf.as_mut_slice(), in_length)?;
+        let salt_start = MAGIC.len() + 1 + 3 * 4;
+        let salt: [u8; 16] = bytes[salt_start..salt_start + 16].try_into()?;
+        let iv: [u8; 16] = bytes[salt_start + 16..HEADER_LENGTH].try_into()?;
 
-    Ok(ciphertext.to_vec())
+        Ok(Header {
+            version,
+            kdf,
+            salt,
+            iv,
+        })
+    }
 }
 
-pub fn decrypt(ciphertext: &Vec<u8>, key: &[u8; 32]) -> ah::Result<Vec<u8>> {
-    let iv: [u8; 16] = random_block();
-    let decryptor = Decryptor::<Aes256>::new(key.into(), &iv.into());
+/// Whether the data starts like an encrypted file does.
+pub fn is_encrypted(data: &[u8]) -> bool {
+    data.starts_with(MAGIC)
+}
 
-    let mut cipher_buf = ciphertext.clone();
-    let plaintext = decryptor.decrypt_padded_mut::<Pkcs7>(cipher_buf.as_mut_slice())?;
+/// Encrypts the plaintext with a key derived from the passphrase, returning
+/// the header, the ciphertext, and a tag authenticating both.
+pub fn encrypt_with_passphrase(
+    plaintext: &[u8],
+    passphrase: &str,
+    kdf: KdfParams,
+) -> ah::Result<Vec<u8>> {
+    let header = Header::new(kdf);
+    let key = header.derive_key(passphrase)?;
+
+    let mut data = header.to_bytes();
+    let mut ciphertext = encrypt(plaintext, &key, &header.iv, &data)?;
+    data.append(&mut ciphertext);
+    Ok(data)
+}
 
-    if plaintext.len() < 16 {
-        return Err(anyhow!(
-            "The decrypted output is smaller than the minimum of 16 bytes. Is {} bytes instead.",
-            plaintext.len()
-        ));
-    }
+/// Decrypts data produced by encrypt_with_passphrase.
+pub fn decrypt_with_passphrase(data: &[u8], passphrase: &str) -> ah::Result<Vec<u8>> {
+    let header = Header::from_bytes(data)?;
+    let key = header.derive_key(passphrase)?;
+    let (header_bytes, ciphertext) = data.split_at(HEADER_LENGTH);
 
-    let mut plaintext = plaintext.to_vec();
-    let _ = plaintext.drain(0..16);
-    let checksum_stored: Vec<u8> = plaintext.drain(0..32).collect();
-    let checksum_actual = get_sha256_digest(&plaintext);
-
-    if checksum_stored != checksum_actual {
-        return Err(anyhow!(
-            "There is a checksum mismatch: {:?} (stored) != {:?} (calculated)",
-            checksum_stored,
-            checksum_actual
-        ));
-    }
+    decrypt(ciphertext, &key, &header.iv, header_bytes)
+        .map_err(|e| anyhow!("Failed to decrypt, is the passphrase correct? {}", e))
+}
 
-    Ok(plaintext.to_vec())
+/// Re-encrypts data produced by encrypt_with_passphrase under another
+/// passphrase, with a fresh salt and IV, and the given KDF parameters.
+pub fn change_passphrase(
+    data: &[u8],
+    old_passphrase: &str,
+    new_passphrase: &str,
+    kdf: KdfParams,
+) -> ah::Result<Vec<u8>> {
+    let plaintext = decrypt_with_passphrase(data, old_passphrase)?;
+    encrypt_with_passphrase(&plaintext, new_passphrase, kdf)
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use proptest::prelude::*;
 
     #[test]
-    fn test_pkcs7_apply_fixed() {
-        let mut sample = vec![1, 2, 3, 4, 5];
-        match pkcs7_apply(&mut sample, 2) {
-            Ok(padding_length) => {
-                assert_eq!(padding_length, 1);
-                assert_eq!(sample, vec![1, 2, 3, 4, 5, 1]);
-            }
-            Err(error) => {
-                panic!("Unexpected error: {:?}", error);
-            }
-        };
+    fn test_header_round_trip() {
+        let header = Header::new(KdfParams::default());
+        let bytes = header.to_bytes();
+
+        assert_eq!(bytes.len(), HEADER_LENGTH);
+        assert_eq!(Header::from_bytes(&bytes).unwrap(), header);
     }
 
+    /// Cheap parameters, as the defaults would slow the tests down.
+    const TEST_KDF: KdfParams = KdfParams {
+        mem_cost: 64,
+        time_cost: 1,
+        lanes: 1,
+    };
+
     #[test]
-    fn test_pkcs7_strip_fixed() {
-        let mut sample = vec![1, 2, 3, 4, 5, 1];
-
-        match pkcs7_strip(&mut sample) {
-            Ok(padding_length) => {
-                assert_eq!(padding_length, 1);
-                assert_eq!(sample, vec![1, 2, 3, 4, 5]);
-            }
-            Err(error) => {
-                panic!("Unexpected error: {:?}", error);
-            }
-        };
+    fn test_passphrase_round_trip() {
+        let plaintext = b"{\"weekly_views\": {}}".to_vec();
+        let data = encrypt_with_passphrase(&plaintext, "hunter2", TEST_KDF).unwrap();
+
+        assert!(is_encrypted(&data));
+        assert_eq!(
+            decrypt_with_passphrase(&data, "hunter2").unwrap(),
+            plaintext
+        );
+        assert!(decrypt_with_passphrase(&data, "hunter3").is_err());
+    }
+
+    #[test]
+    fn test_derive_key_is_salted() {
+        let salt = random_block();
+        let derive = |salt: &[u8]| derive_key(b"hunter2", salt, &TEST_KDF);
+
+        let key = derive(&salt).unwrap();
+
+        assert_ne!(key, [0u8; 32]);
+        assert_eq!(key, derive(&salt).unwrap());
+        assert_ne!(key, derive(&random_block()).unwrap());
+    }
+
+    #[test]
+    fn test_kdf_params_from_header() {
+        let data = encrypt_with_passphrase(b"{}", "hunter2", TEST_KDF).unwrap();
+        assert_eq!(Header::from_bytes(&data).unwrap().kdf, TEST_KDF);
+
+        let mut tampered = Header::from_bytes(&data).unwrap();
+        tampered.kdf.mem_cost = u32::MAX;
+
+        let mut data = data;
+        data[..HEADER_LENGTH].copy_from_slice(&tampered.to_bytes());
+        assert!(decrypt_with_passphrase(&data, "hunter2").is_err());
+
+        tampered.kdf.mem_cost = MAX_MEM_COST + 1;
+        assert!(tampered.kdf.validate().is_err());
+    }
+
+    #[test]
+    fn test_change_passphrase() {
+        let data = encrypt_with_passphrase(b"{}", "hunter2", TEST_KDF).unwrap();
+        let rekeyed = change_passphrase(&data, "hunter2", "correct horse", TEST_KDF).unwrap();
+        let salt = |data: &[u8]| Header::from_bytes(data).unwrap().salt;
+
+        assert_ne!(salt(&rekeyed), salt(&data));
+        assert_eq!(
+            decrypt_with_passphrase(&rekeyed, "correct horse").unwrap(),
+            b"{}"
+        );
+        assert!(decrypt_with_passphrase(&rekeyed, "hunter2").is_err());
+        assert!(change_passphrase(&data, "wrong", "correct horse", TEST_KDF).is_err());
+    }
+
+    proptest! {
+        #[test]
+        fn test_decrypt_inverts_encrypt(
+            plaintext in prop::collection::vec(any::<u8>(), 0..1024),
+            key in any::<[u8; 32]>(),
+            iv in any::<[u8; 16]>(),
+            associated_data in prop::collection::vec(any::<u8>(), 0..64),
+        ) {
+            let ciphertext = encrypt(&plaintext, &key, &iv, &associated_data).unwrap();
+            prop_assert_eq!(decrypt(&ciphertext, &key, &iv, &associated_data).unwrap(), plaintext);
+        }
+
+        #[test]
+        fn test_decrypt_detects_tampering(
+            plaintext in prop::collection::vec(any::<u8>(), 0..1024),
+            key in any::<[u8; 32]>(),
+            iv in any::<[u8; 16]>(),
+            associated_data in prop::collection::vec(any::<u8>(), 1..64),
+            position in any::<prop::sample::Index>(),
+            bit in 0..8u8,
+        ) {
+            let ciphertext = encrypt(&plaintext, &key, &iv, &associated_data).unwrap();
+
+            let mut tampered = ciphertext.clone();
+            tampered[position.index(ciphertext.len())] ^= 1 << bit;
+            prop_assert!(decrypt(&tampered, &key, &iv, &associated_data).is_err());
+
+            let mut tampered = associated_data.clone();
+            tampered[position.index(associated_data.len())] ^= 1 << bit;
+            prop_assert!(decrypt(&ciphertext, &key, &iv, &tampered).is_err());
+
+            let mut tampered = iv;
+            tampered[position.index(iv.len())] ^= 1 << bit;
+            prop_assert!(decrypt(&ciphertext, &key, &tampered, &associated_data).is_err());
+
+            let truncated = &ciphertext[..position.index(ciphertext.len())];
+            prop_assert!(decrypt(truncated, &key, &iv, &associated_data).is_err());
+        }
     }
 }
@@ -0,0 +1,653 @@
+use chrono::{DateTime, Utc};
+use rusqlite::types::Value;
+use rusqlite::{params_from_iter, Connection};
+use std::collections::{BTreeMap, HashMap};
+use std::sync::{Arc, Mutex};
+
+use crate::report::*;
+use crate::timecalc::*;
+
+use anyhow as ah;
+
+/// A table holding one part of the reports of every repository, keyed by the
+/// repository and the key columns.
+struct Table {
+    name: &'static str,
+    keys: &'static [&'static str],
+
+    /// The value columns, along with their types.
+    values: &'static [(&'static str, &'static str)],
+}
+
+const EVENTS: &[(&str, &str)] = &[("count", "INTEGER"), ("uniques", "INTEGER")];
+const SEEN: &[(&str, &str)] = &[("timestamp", "TEXT")];
+
+/// The schema, in the order RepositoryReport::rows lays out the rows.
+const TABLES: [Table; 12] = [
+    Table {
+        name: "hourly_views",
+        keys: &["timestamp"],
+        values: EVENTS,
+    },
+    Table {
+        name: "hourly_clones",
+        keys: &["timestamp"],
+        values: EVENTS,
+    },
+    Table {
+        name: "weekly_views",
+        keys: &["timestamp"],
+        values: EVENTS,
+    },
+    Table {
+        name: "weekly_clones",
+        keys: &["timestamp"],
+        values: EVENTS,
+    },
+    Table {
+        name: "referrers",
+        keys: &["referrer", "timestamp"],
+        values: EVENTS,
+    },
+    Table {
+        name: "paths",
+        keys: &["path", "timestamp"],
+        values: EVENTS,
+    },
+    Table {
+        name: "stars",
+        keys: &["login"],
+        values: SEEN,
+    },
+    Table {
+        name: "forks",
+        keys: &["name"],
+        values: SEEN,
+    },
+    Table {
+        name: "watchers",
+        keys: &["login"],
+        values: SEEN,
+    },
+    // The running totals, by metric, and the all time and last 14 day totals
+    // of every referrer and path, by window.
+    Table {
+        name: "totals",
+        keys: &["metric"],
+        values: EVENTS,
+    },
+    Table {
+        name: "referrer_totals",
+        keys: &["window", "referrer"],
+        values: EVENTS,
+    },
+    Table {
+        name: "path_totals",
+        keys: &["window", "path"],
+        values: EVENTS,
+    },
+];
+
+/// Where the totals are in TABLES, which every saved report has.
+const TOTALS: usize = 9;
+
+const METRICS: [&str; 4] = ["views", "clones", "content_visits", "referrals"];
+
+const ALL_TIME: &str = "all_time";
+const BIWEEKLY: &str = "biweekly";
+
+/// The rows of a table belonging to a single repository, by their keys.
+type Rows = HashMap<Vec<String>, Vec<Value>>;
+
+impl Table {
+    fn create(&self) -> String {
+        let columns: Vec<String> = std::iter::once("repository TEXT NOT NULL".to_string())
+            .chain(self.keys.iter().map(|key| format!("{} TEXT NOT NULL", key)))
+            .chain(
+                self.values
+                    .iter()
+                    .map(|(name, kind)| format!("{} {} NOT NULL", name, kind)),
+            )
+            .collect();
+
+        format!(
+            "CREATE TABLE IF NOT EXISTS {} ({}, PRIMARY KEY (repository, {})) WITHOUT ROWID",
+            self.name,
+            columns.join(", "),
+            self.keys.join(", ")
+        )
+    }
+
+    fn value_names(&self) -> Vec<&str> {
+        self.values.iter().map(|(name, _)| *name).collect()
+    }
+
+    /// Inserts a row, or replaces the values of the row with the same key.
+    fn upsert(&self) -> String {
+        let values = self.value_names();
+        let columns = [&["repository"], self.keys, &values[..]].concat();
+        let placeholders = vec!["?"; columns.len()].join(", ");
+
+        let updates: Vec<String> = values
+            .iter()
+            .map(|name| format!("{0} = excluded.{0}", name))
+            .collect();
+
+        format!(
+            "INSERT INTO {} ({}) VALUES ({}) ON CONFLICT (repository, {}) DO UPDATE SET {}",
+            self.name,
+            columns.join(", "),
+            placeholders,
+            self.keys.join(", "),
+            updates.join(", ")
+        )
+    }
+
+    fn delete(&self) -> String {
+        let conditions: Vec<String> = self.keys.iter().map(|key| format!("{} = ?", key)).collect();
+
+        format!(
+            "DELETE FROM {} WHERE repository = ? AND {}",
+            self.name,
+            conditions.join(" AND ")
+        )
+    }
+
+    /// Whether the table is keyed by, or holds, a timestamp.
+    fn has_timestamp(&self) -> bool {
+        self.keys.contains(&"timestamp") || self.value_names().contains(&"timestamp")
+    }
+
+    fn select(&self) -> String {
+        format!(
+            "SELECT {}, {} FROM {} WHERE repository = ?",
+            self.keys.join(", "),
+            self.value_names().join(", "),
+            self.name
+        )
+    }
+}
+
+/// A database shared by every watched repository.
+pub type SharedDatabase = Arc<Mutex<Database>>;
+
+/// Stores the reports of every repository in a single SQLite database, one
+/// row per hour, week, referrer, path, stargazer and so on, rather than as a
+/// JSON file each that has to be rewritten as a whole. Saves only write the
+/// rows that changed since the last save or load, and every write is an
+/// upsert, so saving the same report twice leaves the database as it was.
+pub struct Database {
+    connection: Connection,
+
+    /// The rows of every repository as last saved or loaded, which the next
+    /// save is compared against.
+    saved: HashMap<String, Vec<Rows>>,
+
+    /// Held while this collector writes to the database, as the rows it
+    /// compares against would go stale if another one did too.
+    lock: Option<std::fs::File>,
+}
+
+impl std::fmt::Debug for Database {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Database")
+            .field("path", &self.connection.path())
+            .finish()
+    }
+}
+
+impl Database {
+    /// Opens the database, creating it and any missing tables.
+    pub fn open(path: &str) -> ah::Result<Self> {
+        let connection = Connection::open(path)
+            .map_err(|e| ah::anyhow!("Failed to open the database {}: {}", path, e))?;
+
+        // Only relevant for a file, an in-memory database has no journal.
+        let _: String = connection.query_row("PRAGMA journal_mode = WAL", [], |row| row.get(0))?;
+
+        for table in &TABLES {
+            connection.execute(&table.create(), [])?;
+        }
+
+        Ok(Database {
+            connection,
+            saved: HashMap::new(),
+            lock: None,
+        })
+    }
+
+    pub fn open_shared(path: &str) -> ah::Result<SharedDatabase> {
+        Ok(Arc::new(Mutex::new(Database::open(path)?)))
+    }
+
+    /// Locks <path>.lock, failing if another collector already has. An
+    /// in-memory database can't be shared, so it isn't locked.
+    pub fn lock(&mut self) -> ah::Result<()> {
+        let path = match self.connection.path() {
+            Some(path) if !path.is_empty() && self.lock.is_none() => path,
+            _ => return Ok(()),
+        };
+
+        let lock_path = format!("{}.lock", path);
+
+        let file = std::fs::File::options()
+            .create(true)
+            .truncate(false)
+            .write(true)
+            .open(&lock_path)
+            .map_err(|e| ah::anyhow!("Failed to open the lock file {}: {}", lock_path, e))?;
+
+        file.try_lock()
+            .map_err(|_| ah::anyhow!("The database {} is locked by another collector", path))?;
+
+        self.lock = Some(file);
+        Ok(())
+    }
+
+    /// Loads the report of a repository, if it has ever been saved.
+    pub fn load(&mut self, repository: &str) -> ah::Result<Option<RepositoryReport>> {
+        let rows = self.select(repository)?;
+
+        // Every saved report has its totals, even if they're all zero.
+        if rows[TOTALS].is_empty() {
+            return Ok(None);
+        }
+
+        let report = RepositoryReport::from_rows(&rows)?;
+        self.saved.insert(repository.into(), rows);
+        Ok(Some(report))
+    }
+
+    /// Saves the report of a repository, returning how many rows had to be
+    /// written or deleted. Everything is written in a single transaction, so
+    /// a failed save leaves the previous one intact.
+    pub fn save(&mut self, repository: &str, report: &RepositoryReport) -> ah::Result<usize> {
+        let rows = report.rows();
+
+        let saved = match self.saved.remove(repository) {
+            Some(saved) => saved,
+            None => self.select(repository)?,
+        };
+
+        let transaction = self.connection.transaction()?;
+        let mut written = 0;
+
+        for ((table, new), old) in TABLES.iter().zip(&rows).zip(&saved) {
+            let mut upsert = transaction.prepare_cached(&table.upsert())?;
+
+            for (key, values) in new {
+                if old.get(key) == Some(values) {
+                    continue;
+                }
+
+                let row = std::iter::once(Value::Text(repository.into()))
+                    .chain(key.iter().cloned().map(Value::Text))
+                    .chain(values.iter().cloned());
+
+                written += upsert.execute(params_from_iter(row))?;
+            }
+
+            let mut delete = transaction.prepare_cached(&table.delete())?;
+
+            for key in old.keys().filter(|key| !new.contains_key(*key)) {
+                let row = std::iter::once(repository).chain(key.iter().map(String::as_str));
+                written += delete.execute(params_from_iter(row))?;
+            }
+        }
+
+        transaction.commit()?;
+        self.saved.insert(repository.into(), rows);
+
+        Ok(written)
+    }
+
+    /// The part of the report of a repository that happened from one time
+    /// until before the other, see RepositoryReport::range, without loading
+    /// the rest of it.
+    pub fn query_range(
+        &self,
+        repository: &str,
+        from: DateTime<Utc>,
+        to: DateTime<Utc>,
+    ) -> ah::Result<Option<RepositoryReport>> {
+        let range = [format_timestamp(&from), format_timestamp(&to)];
+
+        if self
+            .select_rows(&TABLES[TOTALS], repository, None)?
+            .is_empty()
+        {
+            return Ok(None);
+        }
+
+        let mut tables = Vec::with_capacity(TABLES.len());
+
+        for table in &TABLES {
+            tables.push(match table.has_timestamp() {
+                true => self.select_rows(table, repository, Some(&range))?,
+                false => Rows::new(),
+            });
+        }
+
+        Ok(Some(RepositoryReport::from_rows(&tables)?))
+    }
+
+    fn select(&self, repository: &str) -> ah::Result<Vec<Rows>> {
+        TABLES
+            .iter()
+            .map(|table| self.select_rows(table, repository, None))
+            .collect()
+    }
+
+    /// The rows of a repository in a table, optionally only those with a
+    /// timestamp from the first of the range until before the second.
+    fn select_rows(
+        &self,
+        table: &Table,
+        repository: &str,
+        range: Option<&[String; 2]>,
+    ) -> ah::Result<Rows> {
+        let query = match range {
+            Some(_) => format!("{} AND timestamp >= ? AND timestamp < ?", table.select()),
+            None => table.select(),
+        };
+
+        let params =
+            std::iter::once(repository).chain(range.into_iter().flatten().map(String::as_str));
+
+        let mut statement = self.connection.prepare_cached(&query)?;
+        let keys = table.keys.len();
+
+        let rows = statement
+            .query_map(params_from_iter(params), |row| {
+                let key = (0..keys)
+                    .map(|i| row.get(i))
+                    .collect::<Result<Vec<String>, _>>()?;
+
+                let values = (keys..keys + table.values.len())
+                    .map(|i| row.get(i))
+                    .collect::<Result<Vec<Value>, _>>()?;
+
+                Ok((key, values))
+            })?
+            .collect::<Result<Rows, _>>()?;
+
+        Ok(rows)
+    }
+}
+
+fn events_row(events: &QuantifiableEvents) -> Vec<Value> {
+    vec![
+        Value::Integer(events.amount as i64),
+        Value::Integer(events.amount_unique as i64),
+    ]
+}
+
+fn row_events(values: &[Value]) -> ah::Result<QuantifiableEvents> {
+    match values {
+        [Value::Integer(amount), Value::Integer(amount_unique)] => Ok(QuantifiableEvents {
+            amount: *amount as u64,
+            amount_unique: *amount_unique as u64,
+        }),
+        _ => ah::bail!("Expected a count and uniques, got {:?}", values),
+    }
+}
+
+fn row_timestamp(values: &[Value]) -> ah::Result<DateTime<Utc>> {
+    match values {
+        [Value::Text(text)] => parse_time(text),
+        _ => ah::bail!("Expected a timestamp, got {:?}", values),
+    }
+}
+
+impl RepositoryReport {
+    /// The report as the rows of every table, in the order of TABLES.
+    fn rows(&self) -> Vec<Rows> {
+        let events = |map: &BTreeMap<DateTime<Utc>, QuantifiableEvents>| -> Rows {
+            map.iter()
+                .map(|(timestamp, events)| (vec![format_timestamp(timestamp)], events_row(events)))
+                .collect()
+        };
+
+        let timelines =
+            |map: &BTreeMap<String, BTreeMap<DateTime<Utc>, QuantifiableEvents>>| -> Rows {
+                map.iter()
+                    .flat_map(|(key, timeline)| {
+                        timeline.iter().map(move |(timestamp, events)| {
+                            let key = vec![key.clone(), format_timestamp(timestamp)];
+                            (key, events_row(events))
+                        })
+                    })
+                    .collect()
+            };
+
+        let seen = |map: &BTreeMap<String, DateTime<Utc>>| -> Rows {
+            map.iter()
+                .map(|(key, timestamp)| {
+                    let timestamp = Value::Text(format_timestamp(timestamp));
+                    (vec![key.clone()], vec![timestamp])
+                })
+                .collect()
+        };
+
+        let windows = |all_time: &BTreeMap<String, QuantifiableEvents>,
+                       biweekly: &BTreeMap<String, QuantifiableEvents>|
+         -> Rows {
+            let window = |name: &'static str, map: &BTreeMap<String, QuantifiableEvents>| {
+                map.iter()
+                    .map(move |(key, events)| (vec![name.into(), key.clone()], events_row(events)))
+                    .collect::<Vec<_>>()
+            };
+
+            window(ALL_TIME, all_time)
+                .into_iter()
+                .chain(window(BIWEEKLY, biweekly))
+                .collect()
+        };
+
+        let totals = [
+            (self.total_views, self.total_views_unique),
+            (self.total_clones, self.total_clones_unique),
+            (self.total_content_visits, self.total_content_visits_unique),
+            (self.total_referrals, self.total_referrals_unique),
+        ];
+
+        let totals: Rows = METRICS
+            .iter()
+            .zip(totals)
+            .map(|(metric, (amount, amount_unique))| {
+                let events = QuantifiableEvents {
+                    amount,
+                    amount_unique,
+                };
+
+                (vec![metric.to_string()], events_row(&events))
+            })
+            .collect();
+
+        vec![
+            events(&self.hourly_views),
+            events(&self.hourly_clones),
+            events(&self.weekly_views),
+            events(&self.weekly_clones),
+            timelines(&self.weekly_referrals),
+            timelines(&self.weekly_content_visits),
+            seen(&self.stargazers),
+            seen(&self.forks),
+            seen(&self.watchers),
+            totals,
+            windows(&self.all_time_referrals, &self.biweekly_referrals),
+            windows(&self.all_time_content_paths, &self.biweekly_content_visits),
+        ]
+    }
+
+    fn from_rows(rows: &[Rows]) -> ah::Result<Self> {
+        let events = |rows: &Rows| -> ah::Result<BTreeMap<DateTime<Utc>, QuantifiableEvents>> {
+            rows.iter()
+                .map(|(key, values)| Ok((parse_time(&key[0])?, row_events(values)?)))
+                .collect()
+        };
+
+        let timelines = |rows: &Rows| -> ah::Result<BTreeMap<String, BTreeMap<_, _>>> {
+            let mut timelines: BTreeMap<String, BTreeMap<_, _>> = BTreeMap::new();
+
+            for (key, values) in rows {
+                timelines
+                    .entry(key[0].clone())
+                    .or_default()
+                    .insert(parse_time(&key[1])?, row_events(values)?);
+            }
+
+            Ok(timelines)
+        };
+
+        let seen = |rows: &Rows| -> ah::Result<BTreeMap<String, DateTime<Utc>>> {
+            rows.iter()
+                .map(|(key, values)| Ok((key[0].clone(), row_timestamp(values)?)))
+                .collect()
+        };
+
+        let window =
+            |rows: &Rows, name: &str| -> ah::Result<BTreeMap<String, QuantifiableEvents>> {
+                rows.iter()
+                    .filter(|(key, _)| key[0] == name)
+                    .map(|(key, values)| Ok((key[1].clone(), row_events(values)?)))
+                    .collect()
+            };
+
+        let total = |metric: &str| -> ah::Result<QuantifiableEvents> {
+            match rows[TOTALS].get(&vec![metric.to_string()]) {
+                Some(values) => row_events(values),
+                None => Ok(QuantifiableEvents::default()),
+            }
+        };
+
+        let (views, clones) = (total(METRICS[0])?, total(METRICS[1])?);
+        let (content_visits, referrals) = (total(METRICS[2])?, total(METRICS[3])?);
+
+        Ok(RepositoryReport {
+            total_views: views.amount,
+            total_views_unique: views.amount_unique,
+            total_clones: clones.amount,
+            total_clones_unique: clones.amount_unique,
+            total_content_visits: content_visits.amount,
+            total_content_visits_unique: content_visits.amount_unique,
+            total_referrals: referrals.amount,
+            total_referrals_unique: referrals.amount_unique,
+            all_time_referrals: window(&rows[10], ALL_TIME)?,
+            all_time_content_paths: window(&rows[11], ALL_TIME)?,
+            hourly_views: events(&rows[0])?,
+            hourly_clones: events(&rows[1])?,
+            weekly_views: events(&rows[2])?,
+            weekly_clones: events(&rows[3])?,
+            weekly_referrals: timelines(&rows[4])?,
+            weekly_content_visits: timelines(&rows[5])?,
+            biweekly_referrals: window(&rows[10], BIWEEKLY)?,
+            biweekly_content_visits: window(&rows[11], BIWEEKLY)?,
+            stargazers: seen(&rows[6])?,
+            forks: seen(&rows[7])?,
+            watchers: seen(&rows[8])?,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn events(amount: u64, amount_unique: u64) -> QuantifiableEvents {
+        QuantifiableEvents {
+            amount,
+            amount_unique,
+        }
+    }
+
+    fn time(timestamp: &str) -> DateTime<Utc> {
+        parse_time(timestamp).unwrap()
+    }
+
+    fn report() -> RepositoryReport {
+        let mut report = RepositoryReport {
+            total_views: 12,
+            total_views_unique: 5,
+            ..Default::default()
+        };
+
+        report
+            .hourly_views
+            .insert(time("2024-01-01T00:00:00Z"), events(5, 2));
+        report
+            .hourly_views
+            .insert(time("2024-01-01T01:00:00Z"), events(7, 3));
+        report
+            .weekly_referrals
+            .entry("example.com".into())
+            .or_default()
+            .insert(time("2024-01-01T00:00:00Z"), events(3, 1));
+        report
+            .all_time_referrals
+            .insert("example.com".into(), events(9, 4));
+        report
+            .biweekly_referrals
+            .insert("example.com".into(), events(2, 1));
+        report
+            .stargazers
+            .insert("octocat".into(), time("2024-01-01T00:00:00Z"));
+        report
+            .stargazers
+            .insert("hubot".into(), time("2024-01-02T00:00:00Z"));
+
+        report
+    }
+
+    fn json(report: &RepositoryReport) -> serde_json::Value {
+        serde_json::to_value(report).unwrap()
+    }
+
+    #[test]
+    fn test_save_and_load() {
+        let mut database = Database::open(":memory:").unwrap();
+        let report = report();
+
+        assert!(database.load("owner/repo").unwrap().is_none());
+        assert_eq!(database.save("owner/repo", &report).unwrap(), 11);
+        assert!(database.load("owner/other").unwrap().is_none());
+
+        let loaded = database.load("owner/repo").unwrap().unwrap();
+        assert_eq!(json(&loaded), json(&report));
+    }
+
+    #[test]
+    fn test_saves_are_incremental() {
+        let path = std::env::temp_dir().join(format!("chronicler-{}.sqlite", std::process::id()));
+        let path = path.to_str().unwrap();
+        let _ = std::fs::remove_file(path);
+
+        let mut report = report();
+        let mut database = Database::open(path).unwrap();
+        database.save("owner/repo", &report).unwrap();
+
+        // Saving again changes nothing, even from another connection.
+        assert_eq!(database.save("owner/repo", &report).unwrap(), 0);
+        let mut database = Database::open(path).unwrap();
+        assert_eq!(database.save("owner/repo", &report).unwrap(), 0);
+
+        report
+            .hourly_views
+            .insert(time("2024-01-01T01:00:00Z"), events(8, 3));
+        report.stargazers.remove("hubot");
+        assert_eq!(database.save("owner/repo", &report).unwrap(), 2);
+
+        let loaded = Database::open(path)
+            .unwrap()
+            .load("owner/repo")
+            .unwrap()
+            .unwrap();
+
+        drop(database);
+        std::fs::remove_file(path).unwrap();
+        let _ = std::fs::remove_file(format!("{}-wal", path));
+        let _ = std::fs::remove_file(format!("{}-shm", path));
+
+        assert_eq!(json(&loaded), json(&report));
+    }
+}
@@ -0,0 +1,177 @@
+use glob::{MatchOptions, Pattern};
+use std::collections::HashSet;
+
+use crate::api::*;
+use crate::api_models::*;
+use crate::config::*;
+
+use anyhow as ah;
+
+const MATCH_OPTIONS: MatchOptions = MatchOptions {
+    case_sensitive: false,
+    require_literal_separator: true,
+    require_literal_leading_dot: false,
+};
+
+impl DiscoveryConfig {
+    /// Whether a listed repository should be enrolled, see DiscoveryConfig.
+    pub fn accepts(&self, repository: &ModelRepository) -> bool {
+        let matches = |patterns: &Vec<String>| {
+            patterns.iter().any(|pattern| {
+                Pattern::new(pattern)
+                    .map(|pattern| pattern.matches_with(&repository.full_name, MATCH_OPTIONS))
+                    .unwrap_or(false)
+            })
+        };
+
+        let push_access = repository
+            .permissions
+            .as_ref()
+            .map(|permissions| permissions.push)
+            .unwrap_or(false);
+
+        push_access
+            && !repository.disabled
+            && (self.archived || !repository.archived)
+            && (self.forks || !repository.fork)
+            && (self.include.is_empty() || matches(&self.include))
+            && !matches(&self.exclude)
+    }
+
+    pub fn discover(&self, client: &Client) -> ah::Result<ModelRepositories> {
+        let repositories = match &self.org {
+            Some(org) => client.org_repositories(org)?,
+            None => client.user_repositories()?,
+        };
+
+        Ok(repositories
+            .into_iter()
+            .filter(|repository| self.accepts(repository))
+            .collect())
+    }
+}
+
+/// Runs every discovery in the configuration, returning the targets of all
+/// repositories that aren't already listed explicitly. Fails if any discovery
+/// fails, as a partial result would look like repositories had disappeared.
+pub fn discover_targets(config: &Config) -> ah::Result<Vec<RepositoryTarget>> {
+    let mut seen: HashSet<String> = config
+        .targets()
+        .iter()
+        .map(|target| target.name().to_lowercase())
+        .collect();
+
+    let mut targets = Vec::new();
+
+    for discovery in &config.discover {
+        let host = config.host(&discovery.host);
+
+        let token_source = config.token_source(
+            &discovery.token,
+            &discovery.vault_token,
+            &discovery.app,
+            &discovery.auth_file,
+        );
+        let token = token_source.resolve(&host, &config.requests)?;
+        let client = Client::new(host.clone(), token, config.requests.clone());
+
+        let repositories = discovery.discover(&client).map_err(|e| {
+            ah::anyhow!(
+                "Failed to discover the repositories of {}: {}",
+                discovery.org.as_deref().unwrap_or("the authenticated user"),
+                e
+            )
+        })?;
+
+        // Nothing is kept from the lists but the targets, so there's nothing
+        // to save before their responses are cached.
+        client.commit_responses();
+
+        for repository in repositories {
+            let report_path =
+                config.default_report_path(&host, &repository.owner.login, &repository.name);
+
+            let target = RepositoryTarget {
+                archive: config.archive(&report_path),
+                report_path,
+                owner: repository.owner.login,
+                repository: repository.name,
+                host: host.clone(),
+                token: token_source.clone(),
+                requests: config.requests.clone(),
+            };
+
+            if seen.insert(target.name().to_lowercase()) {
+                targets.push(target);
+            }
+        }
+    }
+
+    Ok(targets)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn repository(full_name: &str, push: bool) -> ModelRepository {
+        ModelRepository {
+            full_name: full_name.into(),
+            permissions: Some(ModelPermissions {
+                push,
+                ..Default::default()
+            }),
+            ..Default::default()
+        }
+    }
+
+    fn discovery(include: &[&str], exclude: &[&str]) -> DiscoveryConfig {
+        DiscoveryConfig {
+            org: None,
+            host: None,
+            include: include.iter().map(|x| x.to_string()).collect(),
+            exclude: exclude.iter().map(|x| x.to_string()).collect(),
+            archived: false,
+            forks: false,
+            token: None,
+            vault_token: None,
+            app: None,
+            auth_file: None,
+        }
+    }
+
+    #[test]
+    fn test_discovery_requires_push_access() {
+        let discovery = discovery(&[], &[]);
+        assert!(discovery.accepts(&repository("owner/repo", true)));
+        assert!(!discovery.accepts(&repository("owner/repo", false)));
+        assert!(!discovery.accepts(&ModelRepository::default()));
+    }
+
+    #[test]
+    fn test_discovery_patterns() {
+        let discovery = discovery(&["Owner/*"], &["*/dotfiles"]);
+        assert!(discovery.accepts(&repository("owner/repo", true)));
+        assert!(!discovery.accepts(&repository("owner/dotfiles", true)));
+        assert!(!discovery.accepts(&repository("other/repo", true)));
+        assert!(!discovery.accepts(&repository("owner/nested/repo", true)));
+    }
+
+    #[test]
+    fn test_discovery_archived_and_forks() {
+        let mut archived = repository("owner/old", true);
+        archived.archived = true;
+
+        let mut fork = repository("owner/fork", true);
+        fork.fork = true;
+
+        let mut discovery = discovery(&[], &[]);
+        assert!(!discovery.accepts(&archived));
+        assert!(!discovery.accepts(&fork));
+
+        discovery.archived = true;
+        discovery.forks = true;
+        assert!(discovery.accepts(&archived));
+        assert!(discovery.accepts(&fork));
+    }
+}
@@ -0,0 +1,216 @@
+use log::warn;
+
+use crate::api_error::*;
+use crate::config::*;
+use crate::discovery::*;
+
+use anyhow as ah;
+
+/// Whether a repository can be collected, as far as can be told up front.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Verdict {
+    Ready,
+
+    /// No token could be found for the repository, or it was rejected.
+    InvalidToken(String),
+
+    /// The token is valid, but doesn't have push access, which the traffic
+    /// endpoints require.
+    NoPushAccess,
+
+    /// Any other reason the repository couldn't be checked.
+    Failed(String),
+}
+
+/// The preflight check of a single repository.
+#[derive(Debug, Clone)]
+pub struct Diagnosis {
+    pub name: String,
+
+    /// The scopes of the token, if it's a classic personal access token.
+    pub scopes: Option<String>,
+
+    /// Whether the token has push access, if that could be determined.
+    pub push: Option<bool>,
+
+    pub verdict: Verdict,
+}
+
+impl Diagnosis {
+    pub fn check(target: &RepositoryTarget) -> Self {
+        let mut diagnosis = Diagnosis {
+            name: target.name(),
+            scopes: None,
+            push: None,
+            verdict: Verdict::Ready,
+        };
+
+        let client = match target.client() {
+            Ok(client) => client,
+            Err(error) => {
+                diagnosis.verdict = Verdict::InvalidToken(error.to_string());
+                return diagnosis;
+            }
+        };
+
+        let access = match client
+            .repository(&target.owner, &target.repository)
+            .access()
+        {
+            Ok(access) => access,
+            Err(error) => {
+                diagnosis.verdict = match error {
+                    ApiError::Unauthorized { .. } => {
+                        Verdict::InvalidToken("The token was rejected".into())
+                    }
+                    ApiError::NotFound { .. } => Verdict::Failed(
+                        "The repository doesn't exist, or the token can't see it".into(),
+                    ),
+                    error => Verdict::Failed(error.to_string()),
+                };
+
+                return diagnosis;
+            }
+        };
+
+        diagnosis.scopes = access.scopes;
+        diagnosis.push = access.repository.permissions.map(|p| p.push);
+
+        if diagnosis.push != Some(true) {
+            diagnosis.verdict = Verdict::NoPushAccess;
+        }
+
+        diagnosis
+    }
+
+    pub fn is_ready(&self) -> bool {
+        self.verdict == Verdict::Ready
+    }
+
+    fn columns(&self) -> [String; 5] {
+        let token = match self.verdict {
+            Verdict::InvalidToken(_) => "invalid",
+            Verdict::Failed(_) => "?",
+            _ => "valid",
+        };
+
+        let scopes = match self.scopes.as_deref() {
+            Some("") => "none".into(),
+            Some(scopes) => scopes.to_string(),
+            None => "-".into(),
+        };
+
+        let push = match self.push {
+            Some(true) => "yes",
+            Some(false) => "no",
+            None => "?",
+        };
+
+        let status = match &self.verdict {
+            Verdict::Ready => "ok".into(),
+            Verdict::InvalidToken(reason) | Verdict::Failed(reason) => reason.clone(),
+            Verdict::NoPushAccess => "The token doesn't have push access".into(),
+        };
+
+        [self.name.clone(), token.into(), scopes, push.into(), status]
+    }
+}
+
+/// Lays the diagnoses out as a table, one repository per row.
+pub fn format_table(diagnoses: &[Diagnosis]) -> String {
+    let header = ["REPOSITORY", "TOKEN", "SCOPES", "PUSH", "STATUS"].map(String::from);
+    let rows: Vec<[String; 5]> = std::iter::once(header)
+        .chain(diagnoses.iter().map(Diagnosis::columns))
+        .collect();
+
+    let mut widths = [0; 5];
+
+    for row in &rows {
+        for (width, column) in widths.iter_mut().zip(row) {
+            *width = (*width).max(column.chars().count());
+        }
+    }
+
+    let mut table = String::new();
+
+    for row in &rows {
+        let line = row
+            .iter()
+            .zip(widths)
+            .map(|(column, width)| format!("{:width$}", column, width = width))
+            .collect::<Vec<_>>()
+            .join("  ");
+
+        table.push_str(line.trim_end());
+        table.push('\n');
+    }
+
+    table
+}
+
+/// The doctor command: checks every listed and discovered repository, prints
+/// the table, and fails if any of them can't be collected.
+pub fn run(config: &Config) -> ah::Result<()> {
+    let mut targets = config.targets();
+
+    // A failed discovery is reported, but doesn't stop the listed repositories
+    // from being checked.
+    match discover_targets(config) {
+        Ok(discovered) => targets.extend(discovered),
+        Err(error) => warn!("{}", error),
+    }
+
+    let diagnoses: Vec<Diagnosis> = targets.iter().map(Diagnosis::check).collect();
+    print!("{}", format_table(&diagnoses));
+
+    let failed = diagnoses.iter().filter(|d| !d.is_ready()).count();
+
+    if failed > 0 {
+        ah::bail!(
+            "{} of {} repositories can't be collected",
+            failed,
+            diagnoses.len()
+        );
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_format_table() {
+        let diagnoses = [
+            Diagnosis {
+                name: "owner/repo".into(),
+                scopes: Some("repo, read:org".into()),
+                push: Some(true),
+                verdict: Verdict::Ready,
+            },
+            Diagnosis {
+                name: "org/readonly".into(),
+                scopes: None,
+                push: Some(false),
+                verdict: Verdict::NoPushAccess,
+            },
+            Diagnosis {
+                name: "org/x".into(),
+                scopes: None,
+                push: None,
+                verdict: Verdict::InvalidToken("The token was rejected".into()),
+            },
+        ];
+
+        assert_eq!(
+            format_table(&diagnoses),
+            "\
+REPOSITORY    TOKEN    SCOPES          PUSH  STATUS
+owner/repo    valid    repo, read:org  yes   ok
+org/readonly  valid    -               no    The token doesn't have push access
+org/x         invalid  -               ?     The token was rejected
+"
+        );
+    }
+}
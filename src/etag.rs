@@ -0,0 +1,191 @@
+use log::warn;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::collections::HashMap;
+use std::path::Path;
+use std::sync::{LazyLock, Mutex};
+
+use crate::storage::*;
+
+use anyhow as ah;
+
+/// A response to a GET request, kept so that it can be reused when the API
+/// responds 304 Not Modified to a request made with If-None-Match, which
+/// doesn't count against the rate limit.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct CachedResponse {
+    pub etag: String,
+    pub body: String,
+
+    /// The Link header, needed to follow the pagination of a cached page.
+    #[serde(default)]
+    pub link: Option<String>,
+}
+
+/// Cached responses keyed by the URL they were requested from and the token
+/// they were requested with, see cache_key, persisted as JSON so that they
+/// survive restarts and single runs with --once.
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub struct EtagCache {
+    entries: HashMap<String, CachedResponse>,
+
+    #[serde(skip)]
+    path: Option<String>,
+
+    /// Whether there are entries that haven't been saved yet.
+    #[serde(skip)]
+    dirty: bool,
+}
+
+impl EtagCache {
+    /// Loads the cache from a file, or starts an empty one if there isn't one
+    /// yet. A cache that can't be read is discarded rather than fatal, as it
+    /// only ever saves requests, and is rebuilt by the next cycle anyway.
+    pub fn load(path: &str) -> Self {
+        let cache = match std::fs::File::open(path) {
+            Ok(file) => serde_json::from_reader(file).unwrap_or_else(|error| {
+                warn!("Discarding the unreadable ETag cache {}: {}", path, error);
+                EtagCache::default()
+            }),
+            Err(_) => EtagCache::default(),
+        };
+
+        EtagCache {
+            path: Some(path.into()),
+            ..cache
+        }
+    }
+
+    pub fn get(&self, key: &str) -> Option<&CachedResponse> {
+        self.entries.get(key)
+    }
+
+    pub fn insert(&mut self, key: &str, response: CachedResponse) {
+        if self.entries.get(key) != Some(&response) {
+            self.entries.insert(key.into(), response);
+            self.dirty = true;
+        }
+    }
+
+    /// Writes the cache back to its file, if it was loaded from one and has
+    /// changed since.
+    pub fn save(&mut self) -> ah::Result<()> {
+        let Some(path) = &self.path else {
+            return Ok(());
+        };
+
+        if !self.dirty {
+            return Ok(());
+        }
+
+        if let Some(parent) = Path::new(path).parent() {
+            std::fs::create_dir_all(parent)?;
+        }
+
+        write_atomically(path, &serde_json::to_vec(self)?)
+            .map_err(|e| ah::anyhow!("Failed to save the ETag cache {}: {}", path, e))?;
+
+        self.dirty = false;
+
+        Ok(())
+    }
+}
+
+/// The key a response is cached under: the URL, which includes the host, and
+/// a digest of the token it was requested with, as what the API returns, and
+/// whether it returns anything at all, depends on who's asking. The token
+/// itself never ends up in the cache.
+pub fn cache_key(token: &str, url: &str) -> String {
+    let digest = Sha256::digest(token.as_bytes());
+    let token_id: String = digest[..8].iter().map(|b| format!("{:02x}", b)).collect();
+
+    format!("{} {}", token_id, url)
+}
+
+/// The cache shared by every request, in memory only until load_etag_cache
+/// gives it a file.
+static ETAG_CACHE: LazyLock<Mutex<EtagCache>> = LazyLock::new(|| Mutex::new(EtagCache::default()));
+
+pub fn load_etag_cache(path: &str) {
+    if let Ok(mut cache) = ETAG_CACHE.lock() {
+        *cache = EtagCache::load(path);
+    }
+}
+
+/// Saves the cache. Called only after the reports built from the cached
+/// responses have been saved, so that a crash in between can't leave behind
+/// an ETag for data that never made it into a report.
+pub fn save_etag_cache() -> ah::Result<()> {
+    match ETAG_CACHE.lock() {
+        Ok(mut cache) => cache.save(),
+        Err(_) => ah::bail!("The ETag cache is poisoned"),
+    }
+}
+
+pub fn cached_response(key: &str) -> Option<CachedResponse> {
+    ETAG_CACHE
+        .lock()
+        .ok()
+        .and_then(|cache| cache.get(key).cloned())
+}
+
+/// Responses received by a client that aren't cached yet. A response is only
+/// cached once the data it holds has been saved: were it cached before, and
+/// the cycle failed, the next cycle would be told that nothing had changed,
+/// and never store the data at all. Dropped along with the client otherwise.
+#[derive(Debug, Default)]
+pub struct StagedResponses(Mutex<HashMap<String, CachedResponse>>);
+
+impl StagedResponses {
+    pub fn stage(&self, key: &str, response: CachedResponse) {
+        if let Ok(mut staged) = self.0.lock() {
+            staged.insert(key.into(), response);
+        }
+    }
+
+    /// Moves the staged responses into the cache shared by every request.
+    pub fn commit(&self) {
+        let Ok(mut staged) = self.0.lock() else {
+            return;
+        };
+
+        if let Ok(mut cache) = ETAG_CACHE.lock() {
+            for (key, response) in staged.drain() {
+                cache.insert(&key, response);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_etag_cache_round_trip() {
+        let path = std::env::temp_dir().join(format!("etags-{}.json", std::process::id()));
+        let path = path.to_str().unwrap();
+
+        let response = CachedResponse {
+            etag: "W/\"abc\"".into(),
+            body: "[]".into(),
+            link: None,
+        };
+
+        let key = cache_key("ghp_1", "https://api.github.com/x");
+
+        let mut cache = EtagCache::load(path);
+        cache.insert(&key, response.clone());
+        cache.save().unwrap();
+
+        let loaded = EtagCache::load(path);
+        std::fs::remove_file(path).unwrap();
+
+        assert_eq!(loaded.get(&key), Some(&response));
+
+        // A response is never served to another token, or from another URL.
+        assert_ne!(cache_key("ghp_2", "https://api.github.com/x"), key);
+        assert_ne!(cache_key("ghp_1", "https://ghe.example.com/x"), key);
+        assert!(!key.contains("ghp_1"));
+    }
+}
@@ -0,0 +1,322 @@
+use chrono::{DateTime, Duration, Utc};
+use jsonwebtoken::{Algorithm, EncodingKey, Header};
+use log::debug;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::sync::{Arc, LazyLock, Mutex};
+
+use crate::api::*;
+use crate::api_error::*;
+use crate::api_models::*;
+use crate::logging::*;
+use crate::transport::*;
+
+use anyhow as ah;
+
+/// How long before it expires an installation token is replaced. Tokens last
+/// an hour, and have to outlast e.g. the midnight sampler without expiring.
+const REFRESH_MARGIN_MINUTES: i64 = 10;
+
+/// How long the JWT that's exchanged for an installation token is valid, the
+/// API refuses anything over ten minutes.
+const JWT_LIFETIME_MINUTES: i64 = 9;
+
+/// Authenticates as an installation of a GitHub App, rather than with a
+/// personal access token. The installation ID is in the URL of the settings
+/// page of the installation, e.g. /organizations/{org}/settings/installations/{id}.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct AppConfig {
+    pub app_id: u64,
+    pub installation_id: u64,
+
+    /// The PEM encoded private key generated for the app.
+    pub private_key_file: String,
+}
+
+#[derive(Debug, Serialize)]
+struct Claims {
+    iat: i64,
+    exp: i64,
+    iss: String,
+}
+
+/// An installation token, along with when it expires.
+#[derive(Debug, Clone)]
+pub struct InstallationToken {
+    pub token: AuthToken,
+    pub expires_at: DateTime<Utc>,
+}
+
+impl InstallationToken {
+    pub fn needs_refresh(&self, now: DateTime<Utc>) -> bool {
+        self.expires_at - now < Duration::minutes(REFRESH_MARGIN_MINUTES)
+    }
+}
+
+/// Installation tokens that have been issued so far, keyed by the API base URL,
+/// the app ID, and the installation ID.
+static INSTALLATION_TOKENS: LazyLock<Mutex<HashMap<String, InstallationToken>>> =
+    LazyLock::new(|| Mutex::new(HashMap::new()));
+
+impl AppConfig {
+    /// Signs a short lived JWT with the private key of the app, which is only
+    /// good for requesting installation tokens.
+    pub fn sign_jwt(&self, now: DateTime<Utc>) -> ah::Result<String> {
+        let pem = std::fs::read(&self.private_key_file).map_err(|e| {
+            ah::anyhow!(
+                "Failed to read the private key {}: {}",
+                self.private_key_file,
+                e
+            )
+        })?;
+
+        let key = EncodingKey::from_rsa_pem(&pem).map_err(|e| {
+            ah::anyhow!(
+                "Failed to parse the private key {}: {}",
+                self.private_key_file,
+                e
+            )
+        })?;
+
+        // Backdated, as recommended, in case the clocks aren't quite in sync.
+        let claims = Claims {
+            iat: (now - Duration::seconds(60)).timestamp(),
+            exp: (now + Duration::minutes(JWT_LIFETIME_MINUTES)).timestamp(),
+            iss: self.app_id.to_string(),
+        };
+
+        Ok(jsonwebtoken::encode(
+            &Header::new(Algorithm::RS256),
+            &claims,
+            &key,
+        )?)
+    }
+
+    /// The installation token to authenticate with, issuing a new one if there
+    /// isn't one yet, or if it's about to expire. It's requested like any other
+    /// request to the host, the way the request policy says.
+    pub fn installation_token(
+        &self,
+        host: &ApiHost,
+        policy: &RequestPolicy,
+    ) -> ah::Result<AuthToken> {
+        self.installation_token_via(host, policy, Arc::new(MinreqTransport))
+    }
+
+    fn installation_token_via(
+        &self,
+        host: &ApiHost,
+        policy: &RequestPolicy,
+        transport: Arc<dyn Transport>,
+    ) -> ah::Result<AuthToken> {
+        let key = format!("{}/{}/{}", host.api_url, self.app_id, self.installation_id);
+        let now = Utc::now();
+
+        let cached = INSTALLATION_TOKENS
+            .lock()
+            .ok()
+            .and_then(|tokens| tokens.get(&key).cloned());
+
+        if let Some(cached) = cached.filter(|cached| !cached.needs_refresh(now)) {
+            return Ok(cached.token);
+        }
+
+        let issued = self.request_installation_token(host, policy, transport, now)?;
+
+        debug!(
+            "Issued an installation token for app {}, installation {}, until {}",
+            self.app_id, self.installation_id, issued.expires_at
+        );
+
+        if let Ok(mut tokens) = INSTALLATION_TOKENS.lock() {
+            tokens.insert(key, issued.clone());
+        }
+
+        Ok(issued.token)
+    }
+
+    fn request_installation_token(
+        &self,
+        host: &ApiHost,
+        policy: &RequestPolicy,
+        transport: Arc<dyn Transport>,
+        now: DateTime<Utc>,
+    ) -> ah::Result<InstallationToken> {
+        let jwt = self.sign_jwt(now)?;
+        register_secret(&jwt);
+
+        // Authenticated as the app itself, which the JWT stands in for.
+        let client = Client {
+            host: host.clone(),
+            token: AuthToken(jwt),
+            policy: policy.clone(),
+            transport,
+            staged: Default::default(),
+        };
+
+        let path = format!("/app/installations/{}/access_tokens", self.installation_id);
+        let url = format!("{}{}", host.api_url, path);
+        let response = client.post(&path)?;
+
+        let content = response.as_str().map_err(|e| ApiError::decode(&url, e))?;
+
+        let model: ModelInstallationToken =
+            sj::from_str(content).map_err(|e| ApiError::decode(&url, e))?;
+
+        register_secret(&model.token);
+
+        let expires_at = DateTime::parse_from_rfc3339(&model.expires_at)
+            .map_err(|e| ApiError::decode(&url, e))?
+            .with_timezone(&Utc);
+
+        Ok(InstallationToken {
+            token: AuthToken(model.token),
+            expires_at,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use jsonwebtoken::{DecodingKey, Validation};
+
+    const PRIVATE_KEY: &str = concat!(env!("CARGO_MANIFEST_DIR"), "/tests/fixtures/github-app.pem");
+    const PUBLIC_KEY: &str = include_str!("../tests/fixtures/github-app.pub.pem");
+
+    fn app(installation_id: u64) -> AppConfig {
+        AppConfig {
+            app_id: 1234,
+            installation_id,
+            private_key_file: PRIVATE_KEY.into(),
+        }
+    }
+
+    const POLICY: RequestPolicy = RequestPolicy {
+        timeout: 60,
+        retries: 1,
+        retry_delay: 0,
+        max_retry_delay: 0,
+    };
+
+    /// Stands in for the token exchange endpoint of the installation, answering
+    /// with each of the responses in turn.
+    fn replay(installation_id: u64, responses: Vec<serde_json::Value>) -> Arc<ReplayTransport> {
+        let replay = ReplayTransport::default();
+
+        for mut response in responses {
+            response["url"] = format!(
+                "https://api.github.com/app/installations/{}/access_tokens",
+                installation_id
+            )
+            .into();
+
+            replay.add(sj::from_value(response).unwrap());
+        }
+
+        Arc::new(replay)
+    }
+
+    fn issued(token: &str, lifetime: Duration) -> serde_json::Value {
+        sj::json!({
+            "status": 201,
+            "body": { "token": token, "expires_at": (Utc::now() + lifetime).to_rfc3339() }
+        })
+    }
+
+    #[test]
+    fn test_sign_jwt() {
+        let now = Utc::now();
+        let jwt = app(1).sign_jwt(now).unwrap();
+
+        let mut validation = Validation::new(Algorithm::RS256);
+        validation.set_required_spec_claims(&["exp", "iat", "iss"]);
+        validation.set_issuer(&["1234"]);
+
+        let key = DecodingKey::from_rsa_pem(PUBLIC_KEY.as_bytes()).unwrap();
+        let claims = jsonwebtoken::decode::<serde_json::Value>(&jwt, &key, &validation).unwrap();
+
+        assert_eq!(
+            claims.claims["iat"],
+            (now - Duration::seconds(60)).timestamp()
+        );
+        assert!(
+            claims.claims["exp"].as_i64().unwrap() <= (now + Duration::minutes(10)).timestamp()
+        );
+    }
+
+    #[test]
+    fn test_installation_token_exchange_and_cache() {
+        let replay = replay(42, vec![issued("ghs_installation", Duration::hours(1))]);
+        let token = || app(42).installation_token_via(&ApiHost::default(), &POLICY, replay.clone());
+
+        assert_eq!(token().unwrap().0, "ghs_installation");
+        assert_eq!(token().unwrap().0, "ghs_installation");
+
+        // The second token came from the cache.
+        let sent = replay.sent();
+        assert_eq!(sent.len(), 1);
+        assert_eq!(sent[0].method, Method::Post);
+
+        let authorization = sent[0]
+            .headers
+            .iter()
+            .find(|(name, _)| *name == "Authorization")
+            .map(|(_, value)| value.as_str());
+
+        assert!(authorization.unwrap().starts_with("Bearer ey"));
+    }
+
+    #[test]
+    fn test_installation_token_refresh() {
+        let replay = replay(
+            43,
+            vec![
+                issued("ghs_short", Duration::minutes(5)),
+                issued("ghs_fresh", Duration::hours(1)),
+            ],
+        );
+
+        let token = || app(43).installation_token_via(&ApiHost::default(), &POLICY, replay.clone());
+
+        assert_eq!(token().unwrap().0, "ghs_short");
+        assert_eq!(token().unwrap().0, "ghs_fresh");
+        assert_eq!(replay.sent().len(), 2);
+    }
+
+    #[test]
+    fn test_installation_token_retried() {
+        let replay = replay(
+            45,
+            vec![
+                sj::json!({ "status": 502, "body": "Bad Gateway" }),
+                issued("ghs_retried", Duration::hours(1)),
+            ],
+        );
+
+        let token = app(45)
+            .installation_token_via(&ApiHost::default(), &POLICY, replay.clone())
+            .unwrap();
+
+        assert_eq!(token.0, "ghs_retried");
+        assert_eq!(replay.sent().len(), 2);
+    }
+
+    #[test]
+    fn test_installation_token_rejected() {
+        let replay = replay(
+            44,
+            vec![sj::json!({ "status": 401, "body": { "message": "Bad credentials" } })],
+        );
+
+        let error = app(44)
+            .installation_token_via(&ApiHost::default(), &POLICY, replay)
+            .unwrap_err();
+
+        assert!(matches!(
+            error.downcast_ref::<ApiError>(),
+            Some(ApiError::Unauthorized { .. })
+        ));
+    }
+}
@@ -0,0 +1,210 @@
+use chrono::{SecondsFormat, Utc};
+use log::{Level, LevelFilter, Log, Metadata, Record};
+use serde::Deserialize;
+use std::collections::HashSet;
+use std::io::Write;
+use std::sync::{Mutex, RwLock};
+
+use anyhow as ah;
+
+const REDACTED: &str = "[REDACTED]";
+
+/// How much to log. Quiet only logs warnings and errors, info adds progress
+/// such as completed collection cycles, debug adds every request, and trace
+/// adds the headers of every request and response, with secrets redacted.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum LogLevel {
+    Quiet,
+    #[default]
+    Info,
+    Debug,
+    Trace,
+}
+
+impl std::str::FromStr for LogLevel {
+    type Err = ah::Error;
+
+    fn from_str(s: &str) -> ah::Result<Self> {
+        match s.to_lowercase().as_str() {
+            "quiet" => Ok(LogLevel::Quiet),
+            "info" => Ok(LogLevel::Info),
+            "debug" => Ok(LogLevel::Debug),
+            "trace" => Ok(LogLevel::Trace),
+            _ => ah::bail!(
+                "Unknown log level {}, expected quiet, info, debug or trace",
+                s
+            ),
+        }
+    }
+}
+
+impl From<LogLevel> for LevelFilter {
+    fn from(level: LogLevel) -> Self {
+        match level {
+            LogLevel::Quiet => LevelFilter::Warn,
+            LogLevel::Info => LevelFilter::Info,
+            LogLevel::Debug => LevelFilter::Debug,
+            LogLevel::Trace => LevelFilter::Trace,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct LogConfig {
+    #[serde(default)]
+    pub level: LogLevel,
+
+    /// A file to append every record to as a line of JSON, in addition to the
+    /// human readable records written to stderr.
+    #[serde(default)]
+    pub file: Option<String>,
+}
+
+/// Every token that has been used for a request, so that they can be scrubbed
+/// from records even if they end up in one by accident, e.g. in an error.
+static SECRETS: RwLock<Option<HashSet<String>>> = RwLock::new(None);
+
+/// Registers a secret to be redacted from every record logged from now on.
+pub fn register_secret(secret: &str) {
+    if secret.is_empty() {
+        return;
+    }
+
+    let known = SECRETS
+        .read()
+        .map(|secrets| secrets.as_ref().is_some_and(|s| s.contains(secret)))
+        .unwrap_or(false);
+
+    if known {
+        return;
+    }
+
+    if let Ok(mut secrets) = SECRETS.write() {
+        secrets
+            .get_or_insert_with(HashSet::new)
+            .insert(secret.into());
+    }
+}
+
+pub fn redact(message: &str) -> String {
+    let mut message = message.to_string();
+
+    if let Ok(secrets) = SECRETS.read() {
+        for secret in secrets.iter().flatten() {
+            message = message.replace(secret.as_str(), REDACTED);
+        }
+    }
+
+    message
+}
+
+/// The value of a header as it's safe to log it.
+pub fn redact_header<'a>(name: &str, value: &'a str) -> &'a str {
+    const SENSITIVE: [&str; 3] = ["authorization", "proxy-authorization", "cookie"];
+
+    if SENSITIVE.iter().any(|s| name.eq_ignore_ascii_case(s)) {
+        REDACTED
+    } else {
+        value
+    }
+}
+
+struct Logger {
+    level: LevelFilter,
+    file: Option<Mutex<std::fs::File>>,
+}
+
+impl Log for Logger {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        metadata.level() <= self.level
+    }
+
+    fn log(&self, record: &Record) {
+        if !self.enabled(record.metadata()) {
+            return;
+        }
+
+        let timestamp = Utc::now().to_rfc3339_opts(SecondsFormat::Millis, true);
+        let message = redact(&record.args().to_string());
+
+        eprintln!("{} {:<5} {}", timestamp, record.level(), message);
+
+        if let Some(file) = &self.file {
+            let line = serde_json::json!({
+                "timestamp": timestamp,
+                "level": record.level().as_str(),
+                "target": record.target(),
+                "message": message,
+            });
+
+            if let Ok(mut file) = file.lock() {
+                // There's nowhere left to report a failure to log to.
+                let _ = writeln!(file, "{}", line);
+            }
+        }
+    }
+
+    fn flush(&self) {
+        if let Some(file) = &self.file {
+            if let Ok(mut file) = file.lock() {
+                let _ = file.flush();
+            }
+        }
+    }
+}
+
+pub fn init(config: &LogConfig) -> ah::Result<()> {
+    let file = match &config.file {
+        Some(path) => {
+            let file = std::fs::OpenOptions::new()
+                .create(true)
+                .append(true)
+                .open(path)
+                .map_err(|e| ah::anyhow!("Failed to open the log file {}: {}", path, e))?;
+
+            Some(Mutex::new(file))
+        }
+        None => None,
+    };
+
+    let level = LevelFilter::from(config.level);
+
+    log::set_boxed_logger(Box::new(Logger { level, file }))
+        .map_err(|e| ah::anyhow!("Failed to initialize logging: {}", e))?;
+
+    log::set_max_level(level);
+
+    Ok(())
+}
+
+/// Whether records of the given level are being logged at all, to avoid
+/// formatting expensive records that would be discarded anyway.
+pub fn logs(level: Level) -> bool {
+    level <= log::max_level()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_redact_registered_secret() {
+        register_secret("ghp_0123456789");
+
+        assert_eq!(
+            redact("GET failed with token ghp_0123456789 attached"),
+            "GET failed with token [REDACTED] attached"
+        );
+    }
+
+    #[test]
+    fn test_redact_header() {
+        assert_eq!(redact_header("Authorization", "Bearer x"), REDACTED);
+        assert_eq!(
+            redact_header("Accept", "application/json"),
+            "application/json"
+        );
+    }
+}
@@ -35,72 +35,429 @@ macro_rules! typedef {
 }
 
 mod api;
+mod api_error;
 mod api_models;
+mod archive;
+mod auth;
+mod config;
 mod crypto;
+mod database;
+mod discovery;
+mod doctor;
+mod etag;
+mod github_app;
+mod logging;
+mod ratelimit;
 mod report;
+mod sampler;
+mod schema;
+mod storage;
 mod timecalc;
-// mod watcher;
+mod transport;
+mod vault;
+mod watcher;
 
 use api::*;
-use api_models::*;
-use report::*;
+use config::*;
+use logging::*;
+use storage::*;
+use vault::*;
+use watcher::*;
 
 use anyhow as ah;
-use std::io::Read;
 
 const HELP_TEXT: &str = "
-\\ Hello World
-\\ Test";
+Usage: github-chronicler --config <path> [options]
+       github-chronicler --author <name> --repository <name> [options]
+       github-chronicler doctor [options]
+       github-chronicler rebuild [options]
+       github-chronicler range <from> <to> [options]
+       github-chronicler migrate [options]
+       github-chronicler vault <add|remove> <name> [--vault <path>] [--vault-fd <fd>]
+       github-chronicler vault list [--vault <path>] [--vault-fd <fd>]
 
-const AUTH_FILE: &str = "./auth.secret";
+Periodically collects the traffic metrics of GitHub repositories, and merges
+them into reports on disk that keep growing beyond the 14 days the API
+remembers.
 
-fn read_token(file: String) -> ah::Result<AuthToken> {
-    let mut file = std::fs::OpenOptions::new().read(true).open(file)?;
+doctor checks that the token of every repository is valid and has push access,
+without collecting anything, and prints which repositories can be collected.
 
-    let mut str: String = String::new();
-    file.read_to_string(&mut str)?;
+rebuild recreates every report from its archive, see --archive, keeping the
+previous report as <report>.<timestamp>.bak. A report with history that its
+archive doesn't have is left alone.
 
-    Ok(AuthToken(str.trim().to_string()))
-}
+range prints every report as of from until before to, which are dates or RFC
+3339 timestamps, as JSON, only keeping the traffic, referrer and path drops,
+and stargazers, watchers and forks of that range.
+
+migrate upgrades every report written by an older version to the current
+format right away, rather than when it's next saved.
+
+Options:
+  -c, --config <path>       JSON file listing the repositories to collect.
+  -a, --author <name>       The owner of a single repository to collect.
+  -r, --repository <name>   The name of a single repository to collect.
+  -o, --report <path>       Where the report of that repository is stored.
+                            (./<author>-<repository>.json)
+  -b, --backups <count>     How many daily backups of each report file to
+                            keep as <report>.<timestamp>.bak. (3)
+  -S, --storage <kind>      How reports are stored, one of json,
+                            compressed_json or database. (json)
+  -D, --database <path>     Store every report in this SQLite database instead
+                            of a JSON file each. (./reports.sqlite)
+  -H, --host <name>         The host of that repository, or to discover
+                            repositories on, for GitHub Enterprise Server.
+                            (github.com)
+  -k, --auth-file <path>    File containing the access token, see below.
+  -T, --vault-token <name>  Use the token of this name from the vault instead.
+  -v, --vault <path>        The token vault. (./tokens.vault)
+  -f, --vault-fd <fd>       Read the vault passphrase from this file descriptor
+                            rather than from $CHRONICLER_VAULT_PASSPHRASE, or
+                            a prompt.
+  -p, --passphrase-file <path>
+                            File containing the passphrase to encrypt reports
+                            with. Plain reports are encrypted when next saved.
+  -P, --change-passphrase <path>
+                            Re-encrypt every report with the passphrase in
+                            this file instead, encrypting plain ones, and exit.
+                            Point --passphrase-file at it afterwards.
+  -i, --interval <seconds>  Time between collection cycles. (3600)
+  -t, --timeout <seconds>   Time to wait for a response to a request. (60)
+  -U, --user-agent <agent>  The User-Agent to identify requests by, e.g. with
+                            a contact address. (github-chronicler/<version>)
+  -u, --discover-user       Collect every repository the token can push to.
+  -g, --discover-org <org>  Collect every repository of the organization that
+                            the token can push to.
+  -l, --list                Print the repositories that would be collected,
+                            including discovered ones, and exit.
+  -1, --once                Perform a single collection cycle and exit.
+  -L, --log-level <level>   One of quiet, info, debug or trace. (info)
+  -F, --log-file <path>     Also append every log record to a file, as JSON.
+  -q, --quiet               Only log warnings and errors, same as -L quiet.
+  -A, --archive             Keep every raw snapshot in an archive next to each
+                            report, ./<author>-<repository>.archive.jsonl,
+                            which the report can be rebuilt from.
+  -n, --no-sampling         Don't sample the referrer and popular content
+                            rollover at UTC midnight.
+  -h, --help                Print this help text.
+
+Options given alongside --config override the respective settings in it, other
+than --author, --repository and --report, which can't be used with it, and
+--host and --vault-token, which only apply to the discovery options.
+
+Without a token, vault token, GitHub App, or token file for a repository, the
+token is taken from the first of: ./auth.secret, $GH_TOKEN, $GITHUB_TOKEN, the
+hosts.yml of the gh CLI, and the git credential helper for the host. Hosts other
+than github.com use $GH_ENTERPRISE_TOKEN or $GITHUB_ENTERPRISE_TOKEN instead of
+the first three.
 
-pub fn experimentation() -> ah::Result<()> {
-    let auth_token = read_token(AUTH_FILE.into())?;
+The vault keeps tokens encrypted under a passphrase. vault add reads the token
+from stdin, and creates the vault if it doesn't exist yet.";
 
-    let author: String = "PsychedelicShayna".into();
-    let repository: String = "cursor-locker".into();
+#[derive(Debug, Default)]
+struct Options {
+    help: bool,
+    once: bool,
+    no_sampling: bool,
+    archive: bool,
+    list: bool,
+    discover_user: bool,
+    discover_org: Option<String>,
+    config: Option<String>,
+    author: Option<String>,
+    repository: Option<String>,
+    report: Option<String>,
+    backups: Option<usize>,
+    storage: Option<StorageKind>,
+    database: Option<String>,
+    host: Option<String>,
+    auth_file: Option<String>,
+    passphrase_file: Option<String>,
+    change_passphrase: Option<String>,
+    vault: Option<String>,
+    vault_fd: Option<i32>,
+    vault_token: Option<String>,
+    interval: Option<u64>,
+    timeout: Option<u64>,
+    user_agent: Option<String>,
+    log_level: Option<LogLevel>,
+    log_file: Option<String>,
+}
 
-    // let report = RepositoryReport::request_new(&auth_token, &author, &repository)?;
+impl Options {
+    fn parse(mut args: impl Iterator<Item = String>) -> ah::Result<Self> {
+        let mut options = Options::default();
 
-    let d = request_stargazers(&auth_token, &author, &repository)?;
+        while let Some(arg) = args.next() {
+            let mut value = || {
+                args.next()
+                    .ok_or_else(|| ah::anyhow!("Missing value for argument {}", arg))
+            };
 
-    for s in d {
-        println!("{:?}", s.login);
+            match arg.as_str() {
+                "--help" | "-h" => options.help = true,
+                "--once" | "-1" => options.once = true,
+                "--no-sampling" | "-n" => options.no_sampling = true,
+                "--archive" | "-A" => options.archive = true,
+                "--list" | "-l" => options.list = true,
+                "--discover-user" | "-u" => options.discover_user = true,
+                "--discover-org" | "-g" => options.discover_org = Some(value()?),
+                "--config" | "-c" => options.config = Some(value()?),
+                "--author" | "-a" => options.author = Some(value()?),
+                "--repository" | "-r" => options.repository = Some(value()?),
+                "--report" | "-o" => options.report = Some(value()?),
+                "--backups" | "-b" => options.backups = Some(value()?.parse()?),
+                "--storage" | "-S" => options.storage = Some(value()?.parse()?),
+                "--database" | "-D" => options.database = Some(value()?),
+                "--host" | "-H" => options.host = Some(value()?),
+                "--auth-file" | "-k" => options.auth_file = Some(value()?),
+                "--vault-token" | "-T" => options.vault_token = Some(value()?),
+                "--vault" | "-v" => options.vault = Some(value()?),
+                "--vault-fd" | "-f" => options.vault_fd = Some(value()?.parse()?),
+                "--passphrase-file" | "-p" => options.passphrase_file = Some(value()?),
+                "--change-passphrase" | "-P" => options.change_passphrase = Some(value()?),
+                "--interval" | "-i" => options.interval = Some(value()?.parse()?),
+                "--timeout" | "-t" => options.timeout = Some(value()?.parse()?),
+                "--user-agent" | "-U" => options.user_agent = Some(value()?),
+                "--log-level" | "-L" => options.log_level = Some(value()?.parse()?),
+                "--log-file" | "-F" => options.log_file = Some(value()?),
+                "--quiet" | "-q" => options.log_level = Some(LogLevel::Quiet),
+                unknown => ah::bail!("Unknown argument {}, see --help", unknown),
+            }
+        }
+
+        Ok(options)
     }
 
-    // report.save_json_file("./report.json");
-    // let loaded_report = RepositoryReport::load_json_file("./report.json")?;
-    // loaded_report.save_json_file("./report2.json");
-    Ok(())
+    fn into_config(self) -> ah::Result<Config> {
+        let discovery = DiscoveryConfig {
+            org: None,
+            host: self.host.clone(),
+            include: Vec::new(),
+            exclude: Vec::new(),
+            archived: false,
+            forks: false,
+            token: None,
+            vault_token: self.vault_token.clone(),
+            app: None,
+            auth_file: None,
+        };
+
+        let mut discover = Vec::new();
+
+        if self.discover_user {
+            discover.push(discovery.clone());
+        }
+
+        if let Some(org) = self.discover_org {
+            discover.push(DiscoveryConfig {
+                org: Some(org),
+                ..discovery
+            });
+        }
+
+        let mut config = match (&self.config, self.author, self.repository) {
+            (Some(_), None, None) if self.report.is_some() => {
+                ah::bail!("--report can't be used with --config")
+            }
+
+            // Only discoveries given on the command line can take these from it.
+            (Some(_), None, None)
+                if discover.is_empty() && (self.host.is_some() || self.vault_token.is_some()) =>
+            {
+                ah::bail!(
+                    "--host and --vault-token can only be used with --config alongside a discovery option"
+                )
+            }
+
+            (Some(path), None, None) => Config::load_json_file(path)?,
+
+            (None, author, repository) => Config {
+                interval: DEFAULT_INTERVAL,
+                auth_file: None,
+                app: None,
+                vault_file: VAULT_FILE.into(),
+                report_dir: ".".into(),
+                backups: DEFAULT_BACKUPS,
+                storage: None,
+                database: None,
+                passphrase_file: None,
+                kdf: Default::default(),
+                sampling: true,
+                archive: false,
+                repositories: match (author, repository) {
+                    (Some(owner), Some(repository)) => vec![RepositoryConfig {
+                        owner,
+                        repository,
+                        report: self.report,
+                        host: self.host,
+                        token: None,
+                        vault_token: self.vault_token,
+                        app: None,
+                        auth_file: None,
+                    }],
+                    (None, None) if !discover.is_empty() => Vec::new(),
+                    _ => ah::bail!(
+                        "Either --config, a discovery option, or both --author and --repository are required, see --help"
+                    ),
+                },
+                discover: Vec::new(),
+                discovery_interval: DEFAULT_DISCOVERY_INTERVAL,
+                user_agent: None,
+                hosts: Default::default(),
+                requests: RequestPolicy::default(),
+                log: LogConfig::default(),
+            },
+
+            _ => ah::bail!("--author and --repository can't be used with --config"),
+        };
+
+        config.discover.extend(discover);
+
+        if let Some(interval) = self.interval {
+            config.interval = interval;
+        }
+
+        if let Some(level) = self.log_level {
+            config.log.level = level;
+        }
+
+        if let Some(file) = self.log_file {
+            config.log.file = Some(file);
+        }
+
+        if let Some(user_agent) = self.user_agent {
+            config.user_agent = Some(user_agent);
+        }
+
+        if let Some(timeout) = self.timeout {
+            config.requests.timeout = timeout;
+        }
+
+        if let Some(auth_file) = self.auth_file {
+            config.auth_file = Some(auth_file);
+        }
+
+        if let Some(vault) = self.vault {
+            config.vault_file = vault;
+        }
+
+        if let Some(backups) = self.backups {
+            config.backups = backups;
+        }
+
+        if let Some(storage) = self.storage {
+            config.storage = Some(storage);
+        }
+
+        if let Some(database) = self.database {
+            config.database = Some(database);
+        }
+
+        if let Some(passphrase_file) = self.passphrase_file {
+            config.passphrase_file = Some(passphrase_file);
+        }
+
+        if self.no_sampling {
+            config.sampling = false;
+        }
+
+        if self.archive {
+            config.archive = true;
+        }
+
+        config.validate()?;
+        Ok(config)
+    }
 }
 
 fn main() -> ah::Result<()> {
-    let mut test: bool = false;
+    let mut args = std::env::args().skip(1).peekable();
 
-    for (carg, narg) in std::env::args().zip(std::env::args().skip(1)) {
-        match (&carg.as_str(), &narg.as_str()) {
-            (&"--help" | &"-h", _) => {
-                println!("{}", HELP_TEXT);
-            }
+    if args.peek().is_some_and(|arg| arg == "vault") {
+        return VaultCommand::parse(args.skip(1))?.run();
+    }
+
+    let command =
+        args.next_if(|arg| ["doctor", "rebuild", "range", "migrate"].contains(&arg.as_str()));
 
-            (&"--test" | &"-t", _) => test = true,
+    let range = match command.as_deref() {
+        Some("range") => {
+            let mut time = || -> ah::Result<_> {
+                let time = args
+                    .next()
+                    .ok_or_else(|| ah::anyhow!("range takes a from and to time, see --help"))?;
 
-            _ => {}
+                timecalc::parse_time(&time)
+            };
+
+            Some((time()?, time()?))
         }
+        _ => None,
+    };
+
+    let options = Options::parse(args)?;
+
+    if options.help {
+        println!("{}", HELP_TEXT);
+        return Ok(());
     }
 
-    if test {
-        experimentation()?;
+    let (once, list) = (options.once, options.list);
+    let change_passphrase = options.change_passphrase.clone();
+    let vault_fd = options.vault_fd;
+    let config = options.into_config()?;
+
+    logging::init(&config.log)?;
+
+    // The cache holds the responses the reports are built from, so it would
+    // leak the contents of encrypted reports if it were persisted alongside.
+    if config.passphrase_file.is_none() {
+        etag::load_etag_cache(&config.etag_cache_path());
+    }
+
+    if config.uses_vault() {
+        let passphrase = read_master_passphrase(vault_fd, false)?;
+        unlock_vault(&config.vault_file, &passphrase)?;
+    }
+
+    match command.as_deref() {
+        Some("doctor") => return doctor::run(&config),
+        Some("rebuild") => return archive::run(&config),
+        Some("migrate") => return schema::run(&config),
+        Some("range") => {
+            if let Some((from, to)) = range {
+                return storage::print_range(&config, from, to);
+            }
+        }
+        _ => {}
+    }
+
+    // Before the reports are loaded, which would fail for any that a change
+    // that was cut short already re-encrypted.
+    if let Some(file) = change_passphrase {
+        let passphrase = crypto::read_passphrase(&file)
+            .map_err(|e| ah::anyhow!("Failed to read the passphrase file {}: {}", file, e))?;
+
+        return storage::reencrypt_reports(&config, passphrase);
     }
 
-    Ok(())
+    let mut watcher = Watcher::new(&config)?;
+
+    if list {
+        watcher.discover()?;
+
+        for repository in watcher.repositories() {
+            println!("{} -> {}", repository.name(), repository.location());
+        }
+
+        return Ok(());
+    }
+
+    if once {
+        watcher.cycle()
+    } else {
+        watcher.run()
+    }
 }
@@ -0,0 +1,164 @@
+use std::collections::HashMap;
+use std::sync::{LazyLock, Mutex};
+use std::time::Duration;
+
+use crate::api::*;
+use crate::timecalc::*;
+use crate::transport::*;
+
+/// Once less than this fraction of the budget remains, requests are spread out
+/// evenly over the time left until the reset, rather than sent back to back.
+const PACING_THRESHOLD: f64 = 0.1;
+
+/// How long to wait after a secondary rate limit response that didn't say how
+/// long to wait, as recommended by the API documentation.
+pub const SECONDARY_RATE_LIMIT_DELAY: Duration = Duration::from_secs(60);
+
+/// The rate limit of a token, as last reported by the API through the
+/// X-RateLimit-Limit, X-RateLimit-Remaining and X-RateLimit-Reset headers.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RateLimit {
+    pub limit: u64,
+    pub remaining: u64,
+
+    /// When the budget is replenished, in seconds since the Unix epoch.
+    pub reset: u64,
+}
+
+impl RateLimit {
+    pub fn from_response(response: &Response) -> Option<Self> {
+        let header = |name| find_header(response, name)?.trim().parse::<u64>().ok();
+
+        Some(RateLimit {
+            limit: header("X-RateLimit-Limit")?,
+            remaining: header("X-RateLimit-Remaining")?,
+            reset: header("X-RateLimit-Reset")?,
+        })
+    }
+
+    pub fn until_reset(&self) -> Duration {
+        Duration::from_secs(self.reset.saturating_sub(get_unix_timestamp()))
+    }
+
+    /// Whether the budget is spent, and hasn't been replenished since.
+    pub fn is_exhausted(&self) -> bool {
+        self.remaining == 0 && self.reset > get_unix_timestamp()
+    }
+
+    /// Whether there's budget left for at least the given number of requests.
+    pub fn allows(&self, requests: u64) -> bool {
+        self.remaining >= requests || self.reset <= get_unix_timestamp()
+    }
+
+    /// How long to wait before the next request, so that a nearly spent budget
+    /// lasts until the reset, instead of running out and blocking everything.
+    pub fn pace(&self) -> Duration {
+        if self.is_exhausted() {
+            return self.until_reset();
+        }
+
+        if (self.remaining as f64) >= (self.limit as f64) * PACING_THRESHOLD {
+            return Duration::ZERO;
+        }
+
+        self.until_reset() / (self.remaining.max(1) as u32)
+    }
+}
+
+/// The rate limits of every token that has been used so far, keyed by token,
+/// as every token has a budget of its own.
+static RATE_LIMITS: LazyLock<Mutex<HashMap<String, RateLimit>>> =
+    LazyLock::new(|| Mutex::new(HashMap::new()));
+
+/// The last known rate limit of a token, if any request was made with it yet.
+pub fn rate_limit(token: &AuthToken) -> Option<RateLimit> {
+    RATE_LIMITS
+        .lock()
+        .ok()
+        .and_then(|rate_limits| rate_limits.get(&token.0).copied())
+}
+
+pub fn record_rate_limit(token: &AuthToken, response: &Response) {
+    let Some(rate_limit) = RateLimit::from_response(response) else {
+        return;
+    };
+
+    if let Ok(mut rate_limits) = RATE_LIMITS.lock() {
+        rate_limits.insert(token.0.clone(), rate_limit);
+    }
+}
+
+/// If the response was rejected due to a rate limit, returns how long to wait
+/// before trying again. Primary rate limits say when the budget resets, while
+/// secondary rate limits (too many requests in a short time) say how long to
+/// wait with Retry-After, if anything at all.
+pub fn rate_limit_delay(response: &Response) -> Option<Duration> {
+    if response.status_code != 403 && response.status_code != 429 {
+        return None;
+    }
+
+    let retry_after = find_header(response, "Retry-After")
+        .and_then(|value| value.trim().parse::<u64>().ok())
+        .map(Duration::from_secs);
+
+    if let Some(retry_after) = retry_after {
+        return Some(retry_after);
+    }
+
+    if let Some(rate_limit) = RateLimit::from_response(response) {
+        if rate_limit.remaining == 0 {
+            return Some(rate_limit.until_reset() + Duration::from_secs(1));
+        }
+    }
+
+    let secondary = response
+        .as_str()
+        .map(|body| body.contains("secondary rate limit"))
+        .unwrap_or(false);
+
+    secondary.then_some(SECONDARY_RATE_LIMIT_DELAY)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_pace() {
+        let reset = get_unix_timestamp() + 1000;
+
+        let plenty = RateLimit {
+            limit: 5000,
+            remaining: 4000,
+            reset,
+        };
+
+        let scarce = RateLimit {
+            remaining: 100,
+            ..plenty
+        };
+
+        let spent = RateLimit {
+            remaining: 0,
+            ..plenty
+        };
+
+        assert_eq!(plenty.pace(), Duration::ZERO);
+        assert!(scarce.pace() > Duration::from_secs(9) && scarce.pace() <= Duration::from_secs(10));
+        assert!(spent.is_exhausted());
+        assert!(spent.pace() > Duration::from_secs(990));
+        assert!(!spent.allows(1));
+    }
+
+    #[test]
+    fn test_reset_replenishes() {
+        let spent = RateLimit {
+            limit: 5000,
+            remaining: 0,
+            reset: get_unix_timestamp() - 1,
+        };
+
+        assert!(!spent.is_exhausted());
+        assert!(spent.allows(100));
+    }
+}
@@ -1,8 +1,11 @@
+use chrono::{DateTime, Utc};
 use serde::{Deserialize, Serialize};
-use std::collections::HashMap;
+use std::collections::BTreeMap;
 
 use crate::api::*;
 use crate::api_models::*;
+use crate::crypto::*;
+use crate::storage::*;
 use crate::timecalc::*;
 
 use anyhow as ah;
@@ -15,8 +18,13 @@ pub struct QuantifiableEvents {
 
 type Referrer = String;
 type ContentPath = String;
-type DatestampUtc = String;
+type DatestampUtc = DateTime<Utc>;
+type Login = String;
+type RepositoryName = String;
 
+/// A report as it's persisted, along with its schema version, see
+/// schema::SCHEMA_VERSION, which has to be increased along with a migration
+/// for any change to the fields.
 #[derive(Debug, Deserialize, Serialize, Clone, Default)]
 pub struct RepositoryReport {
     pub total_views: u64,
@@ -31,53 +39,179 @@ pub struct RepositoryReport {
     pub total_referrals: u64,
     pub total_referrals_unique: u64,
 
-    pub all_time_referrals: HashMap<Referrer, QuantifiableEvents>,
-    pub all_time_content_paths: HashMap<ContentPath, QuantifiableEvents>,
+    pub all_time_referrals: BTreeMap<Referrer, QuantifiableEvents>,
+    pub all_time_content_paths: BTreeMap<ContentPath, QuantifiableEvents>,
 
-    pub hourly_views: HashMap<DatestampUtc, QuantifiableEvents>,
-    pub hourly_clones: HashMap<DatestampUtc, QuantifiableEvents>,
+    pub hourly_views: BTreeMap<DatestampUtc, QuantifiableEvents>,
+    pub hourly_clones: BTreeMap<DatestampUtc, QuantifiableEvents>,
 
-    pub weekly_views: HashMap<DatestampUtc, QuantifiableEvents>,
-    pub weekly_clones: HashMap<DatestampUtc, QuantifiableEvents>,
+    pub weekly_views: BTreeMap<DatestampUtc, QuantifiableEvents>,
+    pub weekly_clones: BTreeMap<DatestampUtc, QuantifiableEvents>,
 
-    pub weekly_referrals: HashMap<Referrer, HashMap<DatestampUtc, QuantifiableEvents>>,
-    pub weekly_content_visits: HashMap<ContentPath, HashMap<DatestampUtc, QuantifiableEvents>>,
+    pub weekly_referrals: BTreeMap<Referrer, BTreeMap<DatestampUtc, QuantifiableEvents>>,
+    pub weekly_content_visits: BTreeMap<ContentPath, BTreeMap<DatestampUtc, QuantifiableEvents>>,
+
+    // The last 14 day totals reported by the API, used to detect when a day
+    // falls out of the window. See RepositoryReport::update_popular.
+    pub biweekly_referrals: BTreeMap<Referrer, QuantifiableEvents>,
+    pub biweekly_content_visits: BTreeMap<ContentPath, QuantifiableEvents>,
+
+    // The current stargazers, watchers and forks, along with when they were
+    // first seen, or when the fork was created. Whoever unstars, unwatches,
+    // or deletes their fork is removed.
+    pub stargazers: BTreeMap<Login, DatestampUtc>,
+    pub watchers: BTreeMap<Login, DatestampUtc>,
+    pub forks: BTreeMap<RepositoryName, DatestampUtc>,
 }
 
 impl RepositoryReport {
     pub fn save_json_file(&self, file_path: &str) -> ah::Result<()> {
-        let file = std::fs::File::create(file_path)?;
-        Ok(serde_json::to_writer(file, self)?)
+        write_atomically(file_path, &self.to_json()?)
     }
 
     pub fn load_json_file(file_path: &str) -> ah::Result<Self> {
-        let file = std::fs::File::open(file_path)?;
-        Ok(serde_json::from_reader(file)?)
+        RepositoryReport::from_json(&std::fs::read(file_path)?)
     }
 
-    pub fn request_new(
-        token: &AuthToken,
-        author: &String,
-        repository: &String,
-    ) -> ah::Result<Self> {
-        let api_data_report = ApiDataReport::request(token, author, repository)?;
-        RepositoryReport::new(&api_data_report)
+    /// Saves the report as JSON, encrypted with a key derived from the
+    /// passphrase, see crypto::encrypt_with_passphrase for the format.
+    pub fn save_encrypted(&self, file_path: &str, encryption: &Encryption) -> ah::Result<()> {
+        let plaintext = self.to_json()?;
+        let data = encrypt_with_passphrase(&plaintext, &encryption.passphrase.0, encryption.kdf)?;
+
+        write_atomically(file_path, &data)
     }
 
-    pub fn request_update(
-        &mut self,
-        token: &AuthToken,
-        author: &String,
-        repository: &String,
-    ) -> ah::Result<()> {
-        let api_data_report = ApiDataReport::request(token, author, repository)?;
-        self.update(&api_data_report)
+    pub fn load_encrypted(file_path: &str, passphrase: &Passphrase) -> ah::Result<Self> {
+        let data = std::fs::read(file_path)?;
+        let plaintext = decrypt_with_passphrase(&data, &passphrase.0)?;
+        RepositoryReport::from_json(&plaintext)
+    }
+
+    /// Saves the report encrypted if there's a passphrase, and as plain JSON
+    /// otherwise.
+    pub fn save(&self, file_path: &str, encryption: Option<&Encryption>) -> ah::Result<()> {
+        match encryption {
+            Some(encryption) => self.save_encrypted(file_path, encryption),
+            None => self.save_json_file(file_path),
+        }
+    }
+
+    /// Loads a report whether it's encrypted or not, so that plain reports can
+    /// be encrypted simply by saving them again once a passphrase is set.
+    pub fn load(file_path: &str, passphrase: Option<&Passphrase>) -> ah::Result<Self> {
+        let data = std::fs::read(file_path)?;
+
+        if !is_encrypted(&data) {
+            return RepositoryReport::load_json_file(file_path);
+        }
+
+        match passphrase {
+            Some(passphrase) => RepositoryReport::load_encrypted(file_path, passphrase),
+            None => ah::bail!("The report is encrypted, but no passphrase was given"),
+        }
     }
 
-    pub fn update(&mut self, api_data: &ApiDataReport) -> ah::Result<()> {
-        let timestamp = get_utc_datestamp()?;
-        let fourteen_days_ago = subtract_two_weeks(&timestamp)?;
+    /// Re-encrypts a report under another passphrase, or encrypts it if it's
+    /// still plain, without having to parse it. A report that's already under
+    /// the new passphrase, as left behind by a change that was cut short, comes
+    /// back as None, so that the change can simply be retried.
+    pub fn reencrypt(
+        data: &[u8],
+        passphrase: Option<&Passphrase>,
+        encryption: &Encryption,
+    ) -> ah::Result<Option<Vec<u8>>> {
+        let new_passphrase = &encryption.passphrase.0;
+
+        if !is_encrypted(data) {
+            return Ok(Some(encrypt_with_passphrase(
+                data,
+                new_passphrase,
+                encryption.kdf,
+            )?));
+        }
+
+        let error = match passphrase {
+            Some(old) => match change_passphrase(data, &old.0, new_passphrase, encryption.kdf) {
+                Ok(data) => return Ok(Some(data)),
+                Err(error) => error,
+            },
+            None => ah::anyhow!("The report is encrypted, but no passphrase was given"),
+        };
+
+        match decrypt_with_passphrase(data, new_passphrase) {
+            Ok(_) => Ok(None),
+            Err(_) => Err(error),
+        }
+    }
+
+    /// The part of the report that happened from one time until before the
+    /// other: the hours and weeks of traffic starting then, the days referrers
+    /// and paths dropped out of the window on, and who was first seen then.
+    /// Totals aren't kept over a range, so they're left at zero.
+    pub fn range(&self, from: DateTime<Utc>, to: DateTime<Utc>) -> Self {
+        // An empty range rather than a reversed one, which BTreeMap::range
+        // would panic on.
+        let to = to.max(from);
+        let within = |timestamp: &DatestampUtc| from <= *timestamp && *timestamp < to;
+
+        let events = |map: &BTreeMap<DatestampUtc, QuantifiableEvents>| {
+            map.range(from..to)
+                .map(|(timestamp, events)| (*timestamp, events.clone()))
+                .collect::<BTreeMap<_, _>>()
+        };
+
+        let timelines = |map: &BTreeMap<String, BTreeMap<DatestampUtc, QuantifiableEvents>>| {
+            map.iter()
+                .map(|(key, timeline)| (key.clone(), events(timeline)))
+                .filter(|(_, timeline)| !timeline.is_empty())
+                .collect()
+        };
+
+        let seen = |map: &BTreeMap<String, DatestampUtc>| {
+            map.iter()
+                .filter(|(_, timestamp)| within(timestamp))
+                .map(|(key, timestamp)| (key.clone(), *timestamp))
+                .collect()
+        };
+
+        RepositoryReport {
+            hourly_views: events(&self.hourly_views),
+            hourly_clones: events(&self.hourly_clones),
+            weekly_views: events(&self.weekly_views),
+            weekly_clones: events(&self.weekly_clones),
+            weekly_referrals: timelines(&self.weekly_referrals),
+            weekly_content_visits: timelines(&self.weekly_content_visits),
+            stargazers: seen(&self.stargazers),
+            watchers: seen(&self.watchers),
+            forks: seen(&self.forks),
+            ..Default::default()
+        }
+    }
 
+    /// Merges freshly requested data into the report, returning whether any
+    /// of it changed since the last request. If nothing did, the merge is
+    /// skipped, as it would have no effect.
+    pub fn merge(
+        &mut self,
+        fetched: &Fetched<ApiDataReport>,
+        fetched_at: DateTime<Utc>,
+    ) -> ah::Result<bool> {
+        if !fetched.modified {
+            return Ok(false);
+        }
+
+        self.update(&fetched.data, fetched_at)?;
+        Ok(true)
+    }
+
+    /// Merges data into the report, as of when it was fetched, which decides
+    /// which day any rollover is attributed to.
+    pub fn update(
+        &mut self,
+        api_data: &ApiDataReport,
+        fetched_at: DateTime<Utc>,
+    ) -> ah::Result<()> {
         // Update weekly views.
         for new_week in &api_data.biweekly_views_model.views {
             let new_week_views = new_week.count;
@@ -95,8 +229,7 @@ impl RepositoryReport {
                     amount_unique: new_week_views_unique,
                 };
 
-                self.weekly_views
-                    .insert(new_week_timestamp.clone(), new_week_data);
+                self.weekly_views.insert(*new_week_timestamp, new_week_data);
             }
         }
 
@@ -118,7 +251,7 @@ impl RepositoryReport {
                 };
 
                 self.weekly_clones
-                    .insert(new_week_timestamp.clone(), new_week_data);
+                    .insert(*new_week_timestamp, new_week_data);
             }
         }
 
@@ -147,8 +280,7 @@ impl RepositoryReport {
                     amount_unique: new_hour_views_unique,
                 };
 
-                self.hourly_views
-                    .insert(new_hour_timestamp.clone(), new_hour_data);
+                self.hourly_views.insert(*new_hour_timestamp, new_hour_data);
                 self.total_views += new_hour_views;
                 self.total_views_unique += new_hour_views_unique;
             }
@@ -180,98 +312,157 @@ impl RepositoryReport {
                 };
 
                 self.hourly_clones
-                    .insert(new_hour_timestamp.clone(), new_hour_data);
+                    .insert(*new_hour_timestamp, new_hour_data);
                 self.total_clones += new_hour_clones;
                 self.total_clones_unique += new_hour_clones_unique;
             }
         }
 
-        for new_referral in &api_data.biweekly_referrals_model {
-            let referrals = self
-                .weekly_referrals
-                .entry(new_referral.referrer.clone())
-                .or_insert(HashMap::new());
+        self.update_popular(
+            &api_data.biweekly_referrals_model,
+            &api_data.biweekly_content_visits_model,
+            fetched_at,
+        )?;
 
-            let referral = referrals.get_mut(&fourteen_days_ago);
+        self.update_community(api_data, fetched_at)?;
 
-            // Store the referrals for the last week in this referrer's timeline hashmap,
-            // with a Datestamp as a key, being the date of the day 14 days ago.
-            if let Some(referral) = referral {
-                referral.amount += new_referral.count;
-                referral.amount_unique += new_referral.uniques;
-            } else {
-                referrals.insert(
-                    fourteen_days_ago.clone(),
-                    QuantifiableEvents {
-                        amount: new_referral.count,
-                        amount_unique: new_referral.uniques,
-                    },
-                );
-            }
+        Ok(())
+    }
 
-            // Count the referrer in the HashMap of referrers, together with the total
-            // amount of referrals gained from this referrer, or the existing count.
-            if let Some(referral) = self.all_time_referrals.get_mut(&new_referral.referrer) {
-                referral.amount += new_referral.count;
-                referral.amount_unique += new_referral.uniques;
-            } else {
-                self.all_time_referrals.insert(
-                    new_referral.referrer.clone(),
-                    QuantifiableEvents {
-                        amount: new_referral.count,
-                        amount_unique: new_referral.uniques,
-                    },
-                );
-            }
+    /// Replaces the stargazers, watchers and forks with the latest lists,
+    /// keeping the timestamps of those that were already known. A list that
+    /// couldn't be requested is left as it was.
+    pub fn update_community(
+        &mut self,
+        api_data: &ApiDataReport,
+        fetched_at: DateTime<Utc>,
+    ) -> ah::Result<()> {
+        let timestamp = utc_datestamp(&fetched_at)?;
+
+        let merge = |known: &BTreeMap<String, DatestampUtc>,
+                     latest: Vec<(&String, &DatestampUtc)>| {
+            latest
+                .into_iter()
+                .map(|(key, seen)| {
+                    let seen = known.get(key).unwrap_or(seen);
+                    (key.clone(), *seen)
+                })
+                .collect::<BTreeMap<String, DatestampUtc>>()
+        };
+
+        if let Some(stargazers) = &api_data.stargazers_model {
+            self.stargazers = merge(
+                &self.stargazers,
+                stargazers
+                    .iter()
+                    .map(|stargazer| (&stargazer.login, &timestamp))
+                    .collect(),
+            );
         }
 
-        for new_content_visit in &api_data.biweekly_content_visits_model {
-            let content_visits = self
-                .weekly_content_visits
-                .entry(new_content_visit.path.clone())
-                .or_insert(HashMap::new());
-
-            let content_visit = content_visits.get_mut(&fourteen_days_ago);
-
-            // Store the content_visits for the last week in this referrer's timeline hashmap,
-            // with a Datestamp as a key, being the date of the day 14 days ago.
-            if let Some(content_visit) = content_visit {
-                content_visit.amount += new_content_visit.count;
-                content_visit.amount_unique += new_content_visit.uniques;
-            } else {
-                content_visits.insert(
-                    fourteen_days_ago.clone(),
-                    QuantifiableEvents {
-                        amount: new_content_visit.count,
-                        amount_unique: new_content_visit.uniques,
-                    },
-                );
-            }
+        if let Some(watchers) = &api_data.watchers_model {
+            self.watchers = merge(
+                &self.watchers,
+                watchers
+                    .iter()
+                    .map(|watcher| (&watcher.login, &timestamp))
+                    .collect(),
+            );
+        }
 
-            // Count the referrer in the HashMap of referrers, together with the total
-            // amount of content_visits gained from this referrer, or the existing count.
-            if let Some(content_visit) =
-                self.all_time_content_paths.get_mut(&new_content_visit.path)
-            {
-                content_visit.amount += new_content_visit.count;
-                content_visit.amount_unique += new_content_visit.uniques;
-            } else {
-                self.all_time_content_paths.insert(
-                    new_content_visit.path.clone(),
-                    QuantifiableEvents {
-                        amount: new_content_visit.count,
-                        amount_unique: new_content_visit.uniques,
-                    },
-                );
-            }
+        if let Some(forks) = &api_data.forks_model {
+            self.forks = merge(
+                &self.forks,
+                forks
+                    .iter()
+                    .map(|fork| (&fork.full_name, &fork.created_at))
+                    .collect(),
+            );
         }
 
         Ok(())
     }
 
-    pub fn new(api_data: &ApiDataReport) -> ah::Result<Self> {
-        let fourteen_days_ago = subtract_two_weeks(&get_utc_datestamp()?)?;
+    /// Merges the latest referrer and popular content totals into the report.
+    ///
+    /// These only ever cover the last 14 days as a whole, without timestamps,
+    /// and a total can only shrink when a day falls out of that window. Any
+    /// decrease since the previous call is therefore attributed to the day that
+    /// is now 14 days ago, and the all time totals are the sum of everything
+    /// that fell out of the window plus what is still in it. Returns whether
+    /// any decrease was observed, i.e. whether a rollover was measured.
+    ///
+    /// A decrease can be obscured by traffic that came in after the rollover
+    /// but before this call, which is what the MidnightSampler is for.
+    pub fn update_popular(
+        &mut self,
+        referrals: &ModelReferrerals,
+        content_visits: &ModelContentTrafficBiWeekly,
+        fetched_at: DateTime<Utc>,
+    ) -> ah::Result<bool> {
+        let fourteen_days_ago = subtract_two_weeks(&utc_datestamp(&fetched_at)?)?;
+
+        let new_referrals = referrals.iter().fold(BTreeMap::new(), |mut acc, x| {
+            acc.insert(
+                x.referrer.clone(),
+                QuantifiableEvents {
+                    amount: x.count,
+                    amount_unique: x.uniques,
+                },
+            );
+            acc
+        });
+
+        let new_content_visits = content_visits.iter().fold(BTreeMap::new(), |mut acc, x| {
+            acc.insert(
+                x.path.clone(),
+                QuantifiableEvents {
+                    amount: x.count,
+                    amount_unique: x.uniques,
+                },
+            );
+            acc
+        });
 
+        let referrals_dropped = attribute_rollover(
+            &mut self.weekly_referrals,
+            &self.biweekly_referrals,
+            &new_referrals,
+            &fourteen_days_ago,
+        );
+
+        let content_visits_dropped = attribute_rollover(
+            &mut self.weekly_content_visits,
+            &self.biweekly_content_visits,
+            &new_content_visits,
+            &fourteen_days_ago,
+        );
+
+        self.biweekly_referrals = new_referrals;
+        self.biweekly_content_visits = new_content_visits;
+
+        self.all_time_referrals = sum_all_time(&self.weekly_referrals, &self.biweekly_referrals);
+        self.all_time_content_paths =
+            sum_all_time(&self.weekly_content_visits, &self.biweekly_content_visits);
+
+        self.total_referrals = self.all_time_referrals.values().map(|x| x.amount).sum();
+        self.total_referrals_unique = self
+            .all_time_referrals
+            .values()
+            .map(|x| x.amount_unique)
+            .sum();
+
+        self.total_content_visits = self.all_time_content_paths.values().map(|x| x.amount).sum();
+        self.total_content_visits_unique = self
+            .all_time_content_paths
+            .values()
+            .map(|x| x.amount_unique)
+            .sum();
+
+        Ok(referrals_dropped || content_visits_dropped)
+    }
+
+    pub fn new(api_data: &ApiDataReport, fetched_at: DateTime<Utc>) -> ah::Result<Self> {
         let all_time_views = api_data.daily_views_model.count;
         let all_time_views_unique = api_data.daily_views_model.uniques;
 
@@ -283,9 +474,9 @@ impl RepositoryReport {
                 .biweekly_views_model
                 .views
                 .iter()
-                .fold(HashMap::new(), |mut acc, x| {
+                .fold(BTreeMap::new(), |mut acc, x| {
                     acc.insert(
-                        x.timestamp.clone(),
+                        x.timestamp,
                         QuantifiableEvents {
                             amount: x.count,
                             amount_unique: x.uniques,
@@ -299,9 +490,9 @@ impl RepositoryReport {
                 .biweekly_clones_model
                 .clones
                 .iter()
-                .fold(HashMap::new(), |mut acc, x| {
+                .fold(BTreeMap::new(), |mut acc, x| {
                     acc.insert(
-                        x.timestamp.clone(),
+                        x.timestamp,
                         QuantifiableEvents {
                             amount: x.count,
                             amount_unique: x.uniques,
@@ -315,9 +506,9 @@ impl RepositoryReport {
                 .daily_views_model
                 .views
                 .iter()
-                .fold(HashMap::new(), |mut acc, x| {
+                .fold(BTreeMap::new(), |mut acc, x| {
                     acc.insert(
-                        x.timestamp.clone(),
+                        x.timestamp,
                         QuantifiableEvents {
                             amount: x.count,
                             amount_unique: x.uniques,
@@ -331,9 +522,9 @@ impl RepositoryReport {
                 .daily_clones_model
                 .clones
                 .iter()
-                .fold(HashMap::new(), |mut acc, x| {
+                .fold(BTreeMap::new(), |mut acc, x| {
                     acc.insert(
-                        x.timestamp.clone(),
+                        x.timestamp,
                         QuantifiableEvents {
                             amount: x.count,
                             amount_unique: x.uniques,
@@ -342,85 +533,222 @@ impl RepositoryReport {
                     acc
                 });
 
-        let mut weekly_referrals = HashMap::new();
-        let mut all_time_referrals: HashMap<Referrer, QuantifiableEvents> = HashMap::new();
+        let mut report = RepositoryReport {
+            total_views: all_time_views,
+            total_views_unique: all_time_views_unique,
+            total_clones: all_time_clones,
+            total_clones_unique: all_time_clones_unique,
+            weekly_views,
+            weekly_clones,
+            hourly_views,
+            hourly_clones,
+            ..Default::default()
+        };
 
-        let mut total_referrals = 0;
-        let mut total_referrals_unique = 0;
+        report.update_popular(
+            &api_data.biweekly_referrals_model,
+            &api_data.biweekly_content_visits_model,
+            fetched_at,
+        )?;
 
-        for new_referral in &api_data.biweekly_referrals_model {
-            let referrals = weekly_referrals
-                .entry(new_referral.referrer.clone())
-                .or_insert(HashMap::new());
+        report.update_community(api_data, fetched_at)?;
 
-            referrals.insert(
-                fourteen_days_ago.clone(),
-                QuantifiableEvents {
-                    amount: new_referral.count,
-                    amount_unique: new_referral.uniques,
-                },
-            );
+        Ok(report)
+    }
+}
 
-            all_time_referrals.insert(
-                new_referral.referrer.clone(),
-                QuantifiableEvents {
-                    amount: new_referral.count,
-                    amount_unique: new_referral.uniques,
-                },
-            );
+/// Compares two consecutive 14 day windows of referrers or content paths, and
+/// records any decrease in the timeline of the respective key under the given
+/// day. A key missing from the new window counts as having dropped to zero.
+fn attribute_rollover(
+    timelines: &mut BTreeMap<String, BTreeMap<DatestampUtc, QuantifiableEvents>>,
+    old_window: &BTreeMap<String, QuantifiableEvents>,
+    new_window: &BTreeMap<String, QuantifiableEvents>,
+    day: &DatestampUtc,
+) -> bool {
+    let mut dropped = false;
+
+    for (key, old) in old_window {
+        let new = new_window.get(key).cloned().unwrap_or_default();
+
+        let amount = old.amount.saturating_sub(new.amount);
+        let amount_unique = old.amount_unique.saturating_sub(new.amount_unique);
+
+        if amount == 0 && amount_unique == 0 {
+            continue;
+        }
+
+        let entry = timelines
+            .entry(key.clone())
+            .or_default()
+            .entry(*day)
+            .or_default();
+
+        entry.amount += amount;
+        entry.amount_unique += amount_unique;
+        dropped = true;
+    }
+
+    dropped
+}
+
+/// The all time total of a referrer or content path is everything that has
+/// fallen out of the 14 day window, plus whatever is still inside of it.
+fn sum_all_time(
+    timelines: &BTreeMap<String, BTreeMap<DatestampUtc, QuantifiableEvents>>,
+    window: &BTreeMap<String, QuantifiableEvents>,
+) -> BTreeMap<String, QuantifiableEvents> {
+    let mut all_time: BTreeMap<String, QuantifiableEvents> = BTreeMap::new();
 
-            total_referrals += new_referral.count;
-            total_referrals_unique += new_referral.uniques;
+    for (key, timeline) in timelines {
+        let entry = all_time.entry(key.clone()).or_default();
+
+        for day in timeline.values() {
+            entry.amount += day.amount;
+            entry.amount_unique += day.amount_unique;
         }
+    }
 
-        let mut weekly_content_visits = HashMap::new();
-        let mut all_time_content_paths: HashMap<ContentPath, QuantifiableEvents> = HashMap::new();
+    for (key, events) in window {
+        let entry = all_time.entry(key.clone()).or_default();
+        entry.amount += events.amount;
+        entry.amount_unique += events.amount_unique;
+    }
 
-        let mut total_content_visits = 0;
-        let mut total_content_visits_unique = 0;
+    all_time
+}
 
-        for new_content_visit in &api_data.biweekly_content_visits_model {
-            let content_visits = weekly_content_visits
-                .entry(new_content_visit.path.clone())
-                .or_insert(HashMap::new());
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::transport::*;
+    use std::sync::Arc;
+
+    fn referrer(referrer: &str, count: u64, uniques: u64) -> ModelReferrer {
+        ModelReferrer {
+            count,
+            referrer: referrer.into(),
+            uniques,
+        }
+    }
 
-            content_visits.insert(
-                fourteen_days_ago.clone(),
-                QuantifiableEvents {
-                    amount: new_content_visit.count,
-                    amount_unique: new_content_visit.uniques,
-                },
-            );
+    #[test]
+    fn test_update_popular_attributes_drop() {
+        let mut report = RepositoryReport::default();
+        let fourteen_days_ago = subtract_two_weeks(&utc_datestamp(&Utc::now()).unwrap()).unwrap();
 
-            all_time_content_paths.insert(
-                new_content_visit.path.clone(),
-                QuantifiableEvents {
-                    amount: new_content_visit.count,
-                    amount_unique: new_content_visit.uniques,
-                },
-            );
+        let before = vec![referrer("github.com", 20, 4), referrer("google.com", 3, 1)];
+        let after = vec![referrer("github.com", 15, 3)];
+
+        assert!(!report.update_popular(&before, &vec![], Utc::now()).unwrap());
+        assert!(report.update_popular(&after, &vec![], Utc::now()).unwrap());
+
+        let github = &report.weekly_referrals["github.com"][&fourteen_days_ago];
+        assert_eq!((github.amount, github.amount_unique), (5, 1));
+
+        let google = &report.weekly_referrals["google.com"][&fourteen_days_ago];
+        assert_eq!((google.amount, google.amount_unique), (3, 1));
+
+        assert_eq!(report.all_time_referrals["github.com"].amount, 20);
+        assert_eq!(report.total_referrals, 23);
+        assert_eq!(report.total_referrals_unique, 5);
+    }
 
-            total_content_visits += new_content_visit.count;
-            total_content_visits_unique += new_content_visit.uniques;
+    #[test]
+    fn test_update_popular_is_idempotent() {
+        let mut report = RepositoryReport::default();
+        let window = vec![referrer("github.com", 20, 4)];
+
+        for _ in 0..3 {
+            assert!(!report.update_popular(&window, &vec![], Utc::now()).unwrap());
         }
 
-        return Ok(RepositoryReport {
-            total_views: all_time_views,
-            total_views_unique: all_time_views_unique,
-            total_clones: all_time_clones,
-            total_clones_unique: all_time_clones_unique,
-            all_time_referrals,
-            all_time_content_paths,
-            total_content_visits,
-            total_content_visits_unique,
-            weekly_views,
-            weekly_clones,
-            hourly_views,
-            hourly_clones,
-            weekly_referrals,
-            weekly_content_visits,
-            total_referrals,
-            total_referrals_unique,
-        });
+        assert!(report.weekly_referrals.is_empty());
+        assert_eq!(report.total_referrals, 20);
+    }
+
+    #[test]
+    fn test_collection_cycle() {
+        let fixtures = concat!(
+            env!("CARGO_MANIFEST_DIR"),
+            "/tests/fixtures/replay/octocat-hello-world"
+        );
+
+        let replay = Arc::new(ReplayTransport::load(fixtures).unwrap());
+
+        let client = Client {
+            host: ApiHost::default(),
+            token: AuthToken("replay-collection-cycle".into()),
+            policy: RequestPolicy {
+                retries: 0,
+                ..Default::default()
+            },
+            transport: replay.clone(),
+            staged: Default::default(),
+        };
+
+        let repository = client.repository("octocat", "hello-world");
+        let fetched = ApiDataReport::request(&repository).unwrap();
+        let mut report = RepositoryReport::new(&fetched.data, Utc::now()).unwrap();
+        client.commit_responses();
+
+        assert_eq!((report.total_views, report.total_views_unique), (7, 4));
+        assert_eq!((report.total_clones, report.total_clones_unique), (2, 1));
+        assert_eq!(
+            report.weekly_views[&parse_time("2023-10-09T00:00:00Z").unwrap()].amount,
+            18
+        );
+        assert_eq!(report.total_referrals, 26);
+        assert_eq!(report.stargazers.len(), 3);
+        assert!(report.watchers.contains_key("octocat"));
+
+        // Every endpoint answers 304 to the ETag it was last requested with.
+        let fetched = ApiDataReport::request(&repository).unwrap();
+        assert!(!report.merge(&fetched, Utc::now()).unwrap());
+
+        replay.replace(
+            serde_json::from_value(serde_json::json!({
+                "url": "https://api.github.com/repos/octocat/hello-world/traffic/views?per=day",
+                "headers": { "ETag": "W/\"views-day-2\"" },
+                "body": {
+                    "count": 12,
+                    "uniques": 5,
+                    "views": [
+                        { "timestamp": "2023-10-14T00:00:00Z", "count": 3, "uniques": 2 },
+                        { "timestamp": "2023-10-15T00:00:00Z", "count": 4, "uniques": 2 },
+                        { "timestamp": "2023-10-16T00:00:00Z", "count": 5, "uniques": 1 }
+                    ]
+                }
+            }))
+            .unwrap(),
+        );
+
+        let fetched = ApiDataReport::request(&repository).unwrap();
+        assert!(report.merge(&fetched, Utc::now()).unwrap());
+        assert_eq!((report.total_views, report.total_views_unique), (12, 5));
+        assert_eq!(report.hourly_views.len(), 3);
+
+        let requests = replay.requests();
+        assert_eq!(requests.len(), 3 * 10);
+        assert!(requests.contains(
+            &"https://api.github.com/repos/octocat/hello-world/stargazers?per_page=100&page=2"
+                .into()
+        ));
+
+        // A list that fails is left as it was, without failing the traffic.
+        replay.replace(
+            serde_json::from_value(serde_json::json!({
+                "url": "https://api.github.com/repos/octocat/hello-world/stargazers?per_page=100",
+                "status": 500
+            }))
+            .unwrap(),
+        );
+
+        let fetched = ApiDataReport::request(&repository).unwrap();
+        assert!(fetched.data.stargazers_model.is_none());
+        assert!(fetched.data.watchers_model.is_some());
+
+        report.update(&fetched.data, Utc::now()).unwrap();
+        assert_eq!(report.stargazers.len(), 3);
     }
 }
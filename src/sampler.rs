@@ -0,0 +1,163 @@
+use chrono::Utc;
+use log::warn;
+use std::time::{Duration, Instant};
+
+use crate::api::*;
+use crate::archive::*;
+use crate::auth::*;
+use crate::config::*;
+use crate::ratelimit::*;
+use crate::report::*;
+use crate::timecalc::*;
+
+use anyhow as ah;
+
+/// Measures how much referrer and popular content traffic falls out of the 14
+/// day window at UTC midnight, following the approach described at the top of
+/// api.rs: poll shortly before the rollover so the last pre-midnight snapshot
+/// is as fresh as possible, then poll densely after it until a drop shows up,
+/// leaving as little room as possible for new traffic to obscure the drop.
+#[derive(Debug, Clone)]
+pub struct MidnightSampler {
+    /// How long before midnight sampling begins.
+    pub lead: Duration,
+
+    /// The delay between consecutive polls, both before and after midnight.
+    pub poll_interval: Duration,
+
+    /// How long after midnight to keep polling when no drop is observed,
+    /// e.g. because nothing happened 14 days ago, or because the API is late.
+    pub deadline: Duration,
+}
+
+impl Default for MidnightSampler {
+    fn default() -> Self {
+        MidnightSampler {
+            lead: Duration::from_secs(2 * 60),
+            poll_interval: Duration::from_secs(15),
+            deadline: Duration::from_secs(30 * 60),
+        }
+    }
+}
+
+impl MidnightSampler {
+    /// If sampling has to begin before the given delay runs out, returns how
+    /// long to wait until it should begin instead.
+    pub fn starts_within(&self, delay: Duration) -> ah::Result<Option<Duration>> {
+        let until_midnight = next_utc_day()?.to_std()?;
+        let until_start = until_midnight.saturating_sub(self.lead);
+
+        Ok((until_start <= delay).then_some(until_start))
+    }
+
+    /// Samples the upcoming rollover into the reports of all targets at once.
+    /// Blocks until either a drop has been observed for every target, or until
+    /// the deadline passes. Failed polls are skipped rather than aborting, as
+    /// a missed rollover can't be measured again.
+    pub fn sample(&self, targets: &mut [RolloverTarget]) -> ah::Result<()> {
+        // Fixed up front, as a round of polls that runs past midnight would
+        // otherwise have the next one aim for the midnight after.
+        let midnight = Utc::now() + next_utc_day()?;
+
+        loop {
+            for target in targets.iter_mut() {
+                target.poll();
+            }
+
+            let Ok(until_midnight) = (midnight - Utc::now()).to_std() else {
+                break;
+            };
+
+            if until_midnight <= self.poll_interval {
+                std::thread::sleep(until_midnight);
+                break;
+            }
+
+            std::thread::sleep(self.poll_interval);
+        }
+
+        let deadline = Instant::now() + self.deadline;
+
+        loop {
+            for target in targets.iter_mut().filter(|target| !target.dropped) {
+                target.dropped = target.poll();
+            }
+
+            if targets.iter().all(|target| target.dropped) || Instant::now() >= deadline {
+                return Ok(());
+            }
+
+            std::thread::sleep(self.poll_interval);
+        }
+    }
+}
+
+/// A repository taking part in the sampling of a rollover.
+pub struct RolloverTarget<'a> {
+    pub report: &'a mut RepositoryReport,
+    pub target: &'a RepositoryTarget,
+    pub client: Client,
+
+    /// Whether a drop has been observed for this repository after midnight.
+    pub dropped: bool,
+}
+
+impl RolloverTarget<'_> {
+    fn poll(&mut self) -> bool {
+        // Installation tokens can expire while sampling, the cached one is only
+        // replaced when it's about to.
+        if let TokenSource::App(_) = self.target.token {
+            match self.target.client() {
+                Ok(client) => self.client = client,
+                Err(error) => warn!(
+                    "Failed to refresh the token of {}: {}",
+                    self.target.name(),
+                    error
+                ),
+            }
+        }
+
+        // Waiting for the rate limit to reset here would hold up every other
+        // target too, so the poll is skipped instead.
+        if rate_limit(&self.client.token).is_some_and(|rate_limit| !rate_limit.allows(2)) {
+            return false;
+        }
+
+        let repository = self
+            .client
+            .repository(&self.target.owner, &self.target.repository);
+
+        let result = (|| -> ah::Result<bool> {
+            let fetched_at = Utc::now();
+            let referrals = repository.referrers_weekly()?;
+            let content_visits = repository.popular_paths_weekly()?;
+
+            // Nothing can have dropped out of windows that haven't changed.
+            if !referrals.modified && !content_visits.modified {
+                return Ok(false);
+            }
+
+            if let Some(archive) = &self.target.archive {
+                let snapshot = Snapshot::Popular {
+                    referrals: referrals.data.clone(),
+                    content_visits: content_visits.data.clone(),
+                };
+
+                archive.append(fetched_at, snapshot)?;
+            }
+
+            self.report
+                .update_popular(&referrals.data, &content_visits.data, fetched_at)
+        })();
+
+        result.unwrap_or_else(|error| {
+            warn!(
+                "Failed to poll {} during the midnight rollover: {}",
+                self.target.name(),
+                error
+            );
+
+            false
+        })
+    }
+}
@@ -0,0 +1,205 @@
+use log::{info, warn};
+use serde_json::{Map, Value};
+
+use crate::config::*;
+use crate::discovery::*;
+use crate::report::*;
+use crate::storage::*;
+
+use anyhow as ah;
+
+/// The version of the report format written by this version of the collector.
+/// Every report records the version it was written with, and is upgraded
+/// through MIGRATIONS when loaded, so that older reports never fail to load
+/// just because a field was added or changed.
+///
+/// 1. The original format.
+/// 2. Adds the last 14 day referrer and path totals, biweekly_referrals and
+///    biweekly_content_visits, which the midnight sampler compares against,
+///    as well as stargazers, watchers and forks, and records the version as
+///    schema_version.
+pub const SCHEMA_VERSION: u64 = 2;
+
+const VERSION_FIELD: &str = "schema_version";
+
+type Migration = fn(&mut Map<String, Value>) -> ah::Result<()>;
+
+/// Upgrades a report from the version at the same index plus one, to the next.
+const MIGRATIONS: [Migration; 1] = [|report| {
+    insert_empty(
+        report,
+        &[
+            "biweekly_referrals",
+            "biweekly_content_visits",
+            "stargazers",
+            "watchers",
+            "forks",
+        ],
+    );
+    Ok(())
+}];
+
+/// Adds fields that didn't exist yet as empty maps. An empty window isn't
+/// compared against, it's filled in by the next poll instead.
+fn insert_empty(report: &mut Map<String, Value>, fields: &[&str]) {
+    for field in fields {
+        report
+            .entry(*field)
+            .or_insert_with(|| Value::Object(Map::new()));
+    }
+}
+
+/// The version a report was written with, where reports from before versions
+/// were recorded are of the original format.
+pub fn version_of(report: &Map<String, Value>) -> ah::Result<u64> {
+    match report.get(VERSION_FIELD) {
+        Some(version) => version
+            .as_u64()
+            .ok_or_else(|| ah::anyhow!("Invalid {}: {}", VERSION_FIELD, version)),
+        None => Ok(1),
+    }
+}
+
+/// Upgrades a report to SCHEMA_VERSION, returning the version it was at.
+/// Fails for reports written by a newer version of the collector, rather than
+/// losing whatever it added when the report is saved again.
+pub fn migrate(report: &mut Value) -> ah::Result<u64> {
+    let report = report
+        .as_object_mut()
+        .ok_or_else(|| ah::anyhow!("The report isn't a JSON object"))?;
+
+    let version = version_of(report)?;
+
+    if version > SCHEMA_VERSION {
+        ah::bail!(
+            "The report was written with schema version {}, which is newer than {}, the latest this collector supports",
+            version,
+            SCHEMA_VERSION
+        );
+    }
+
+    if version == 0 {
+        ah::bail!("Invalid {}: 0", VERSION_FIELD);
+    }
+
+    for migration in &MIGRATIONS[version as usize - 1..] {
+        migration(report)?;
+    }
+
+    report.insert(VERSION_FIELD.into(), SCHEMA_VERSION.into());
+    Ok(version)
+}
+
+impl RepositoryReport {
+    /// The report as JSON, recording the schema version.
+    pub fn to_json(&self) -> ah::Result<Vec<u8>> {
+        let mut report = serde_json::to_value(self)?;
+
+        if let Some(report) = report.as_object_mut() {
+            report.insert(VERSION_FIELD.into(), SCHEMA_VERSION.into());
+        }
+
+        Ok(serde_json::to_vec(&report)?)
+    }
+
+    /// Parses a report written with any schema version up to the current one.
+    pub fn from_json(data: &[u8]) -> ah::Result<Self> {
+        let mut report: Value = serde_json::from_slice(data)?;
+        let version = migrate(&mut report)?;
+
+        if version < SCHEMA_VERSION {
+            info!(
+                "Upgrading a report from schema version {} to {}",
+                version, SCHEMA_VERSION
+            );
+        }
+
+        if let Some(report) = report.as_object_mut() {
+            report.remove(VERSION_FIELD);
+        }
+
+        Ok(serde_json::from_value(report)?)
+    }
+}
+
+/// The migrate command: upgrades every listed and discovered report to the
+/// current schema version right away, rather than the next time it's saved.
+pub fn run(config: &Config) -> ah::Result<()> {
+    let backend = StorageBackend::new(config)?;
+    let mut targets = config.targets();
+
+    match discover_targets(config) {
+        Ok(discovered) => targets.extend(discovered),
+        Err(error) => warn!("{}", error),
+    }
+
+    for target in &targets {
+        let mut storage = backend.open(target);
+        storage.lock()?;
+
+        let Some(report) = storage.load()? else {
+            continue;
+        };
+
+        storage.upsert_snapshot(&report)?;
+
+        info!(
+            "Saved {} with schema version {}",
+            storage.location(),
+            SCHEMA_VERSION
+        );
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::timecalc::*;
+
+    fn fixture(version: u64) -> Vec<u8> {
+        std::fs::read(format!("tests/fixtures/reports/v{}.json", version)).unwrap()
+    }
+
+    #[test]
+    fn test_every_version_migrates() {
+        for version in 1..=SCHEMA_VERSION {
+            let mut report: Value = serde_json::from_slice(&fixture(version)).unwrap();
+            assert_eq!(migrate(&mut report).unwrap(), version);
+            assert_eq!(report[VERSION_FIELD], SCHEMA_VERSION);
+
+            let report = RepositoryReport::from_json(&fixture(version)).unwrap();
+            assert_eq!((report.total_views, report.total_views_unique), (12, 5));
+            assert_eq!(
+                report.hourly_views[&parse_time("2024-01-01T01:00:00Z").unwrap()].amount,
+                7
+            );
+            assert_eq!(report.weekly_referrals["example.com"].len(), 1);
+
+            assert_eq!(report.biweekly_referrals.len(), (version >= 2) as usize);
+            assert_eq!(report.stargazers.len(), (version >= 2) as usize);
+
+            // Saving records the current version, which loads as it was.
+            let json = report.to_json().unwrap();
+            let resaved: Value = serde_json::from_slice(&json).unwrap();
+            assert_eq!(resaved[VERSION_FIELD], SCHEMA_VERSION);
+            assert!(resaved["hourly_views"]["2024-01-01T01:00:00Z"].is_object());
+
+            let reloaded = RepositoryReport::from_json(&json).unwrap();
+            assert_eq!(
+                serde_json::to_value(&reloaded).unwrap(),
+                serde_json::to_value(&report).unwrap()
+            );
+        }
+    }
+
+    #[test]
+    fn test_newer_versions_are_refused() {
+        let mut report: Value = serde_json::from_slice(&fixture(SCHEMA_VERSION)).unwrap();
+        report[VERSION_FIELD] = (SCHEMA_VERSION + 1).into();
+
+        let data = serde_json::to_vec(&report).unwrap();
+        assert!(RepositoryReport::from_json(&data).is_err());
+    }
+}
@@ -0,0 +1,883 @@
+use chrono::{DateTime, Utc};
+use flate2::read::GzDecoder;
+use flate2::write::GzEncoder;
+use flate2::Compression;
+use serde::Deserialize;
+use std::collections::BTreeMap;
+use std::fmt::Debug;
+use std::fs::File;
+use std::io::{Read, Write};
+use std::path::Path;
+
+use crate::config::*;
+use crate::crypto::*;
+use crate::database::*;
+use crate::discovery::*;
+use crate::report::*;
+
+use anyhow as ah;
+
+/// How backups are timestamped, sorting the same as the times they stand for.
+const BACKUP_TIMESTAMP: &str = "%Y%m%dT%H%M%SZ";
+
+/// How old the latest backup of a file has to be before a save backs it up
+/// again, so that the backups kept span days rather than the last few saves.
+const BACKUP_INTERVAL_HOURS: i64 = 24;
+
+/// Where reports are stored.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum StorageKind {
+    /// A JSON file per report, see RepositoryReport::save.
+    Json,
+
+    /// A gzipped JSON file per report, at the report path with .gz appended.
+    CompressedJson,
+
+    /// A single SQLite database for every report, see Database.
+    Database,
+}
+
+impl std::str::FromStr for StorageKind {
+    type Err = ah::Error;
+
+    fn from_str(kind: &str) -> ah::Result<Self> {
+        match kind {
+            "json" => Ok(StorageKind::Json),
+            "compressed_json" => Ok(StorageKind::CompressedJson),
+            "database" => Ok(StorageKind::Database),
+            _ => ah::bail!(
+                "Unknown storage {}, expected json, compressed_json or database",
+                kind
+            ),
+        }
+    }
+}
+
+/// The history of a single repository, wherever it's kept.
+pub trait Storage: Debug + Send {
+    /// The stored report, if there is one yet.
+    fn load(&mut self) -> ah::Result<Option<RepositoryReport>>;
+
+    /// Stores the report as it is now, in place of what was stored before.
+    fn upsert_snapshot(&mut self, report: &RepositoryReport) -> ah::Result<()>;
+
+    /// The part of the stored report that happened from one time until before
+    /// the other, see RepositoryReport::range.
+    fn query_range(
+        &mut self,
+        from: DateTime<Utc>,
+        to: DateTime<Utc>,
+    ) -> ah::Result<Option<RepositoryReport>> {
+        Ok(self.load()?.map(|report| report.range(from, to)))
+    }
+
+    /// Makes sure no other collector can store the report until this one is
+    /// done with it, failing if another one already is. Storing the report
+    /// takes the lock too, if it hasn't been yet.
+    fn lock(&mut self) -> ah::Result<()>;
+
+    /// Re-encrypts the stored report under another passphrase in memory, or
+    /// encrypts it if it's still plain, returning what write_reencrypted is to
+    /// write. Nothing is written yet, so that every report can be checked
+    /// before any of them is changed.
+    fn reencrypt(&mut self, encryption: &Encryption) -> ah::Result<Option<Vec<u8>>>;
+
+    /// Writes what reencrypt returned, if anything, and encrypts every later
+    /// snapshot under the new passphrase too.
+    fn write_reencrypted(
+        &mut self,
+        data: Option<Vec<u8>>,
+        encryption: Encryption,
+    ) -> ah::Result<()>;
+
+    /// Keeps the stored report as a timestamped backup right away, however
+    /// many are kept otherwise, returning where. Storage without a file of its
+    /// own has nothing to back up.
+    fn backup(&mut self) -> ah::Result<Option<String>> {
+        Ok(None)
+    }
+
+    /// Where the report is stored, to tell the user.
+    fn location(&self) -> String;
+}
+
+/// The file a report is stored in, which is replaced as a whole on every save,
+/// see write_atomically. Whoever saves it holds a lock on <path>.lock, and
+/// the versions it replaces are kept as <path>.<timestamp>.bak, at most one a
+/// day, up to the given number of the latest ones.
+#[derive(Debug)]
+pub struct ReportFile {
+    pub path: String,
+    pub backups: usize,
+    lock: Option<File>,
+}
+
+impl ReportFile {
+    pub fn new(path: &str, backups: usize) -> Self {
+        ReportFile {
+            path: path.into(),
+            backups,
+            lock: None,
+        }
+    }
+
+    pub fn exists(&self) -> bool {
+        Path::new(&self.path).exists()
+    }
+
+    pub fn lock(&mut self) -> ah::Result<()> {
+        if self.lock.is_some() {
+            return Ok(());
+        }
+
+        create_parent_dir(&self.path)?;
+        let lock_path = format!("{}.lock", self.path);
+
+        let file = File::options()
+            .create(true)
+            .truncate(false)
+            .write(true)
+            .open(&lock_path)
+            .map_err(|e| ah::anyhow!("Failed to open the lock file {}: {}", lock_path, e))?;
+
+        file.try_lock().map_err(|e| match e {
+            std::fs::TryLockError::WouldBlock => ah::anyhow!(
+                "{} is locked by another collector, see {}",
+                self.path,
+                lock_path
+            ),
+            std::fs::TryLockError::Error(e) => {
+                ah::anyhow!("Failed to lock {}: {}", lock_path, e)
+            }
+        })?;
+
+        self.lock = Some(file);
+        Ok(())
+    }
+
+    /// Locks the file, and backs up the current version before it's replaced,
+    /// unless the latest backup is younger than BACKUP_INTERVAL_HOURS.
+    pub fn prepare_write(&mut self) -> ah::Result<()> {
+        self.lock()?;
+
+        if self.backups > 0 && self.exists() && self.backup_due()? {
+            self.backup()?;
+        }
+
+        Ok(())
+    }
+
+    pub fn write(&mut self, data: &[u8]) -> ah::Result<()> {
+        self.prepare_write()?;
+        write_atomically(&self.path, data)
+    }
+
+    /// Links the current version to a timestamped backup, and deletes the
+    /// backups beyond the number to keep, oldest first.
+    fn backup(&self) -> ah::Result<()> {
+        self.link_backup()?;
+        self.rotate_backups()
+    }
+
+    /// Links the current version to a timestamped backup, returning its path.
+    /// A backup taken in the same second as the last one is skipped.
+    pub fn link_backup(&self) -> ah::Result<String> {
+        let backup = format!("{}.{}.bak", self.path, Utc::now().format(BACKUP_TIMESTAMP));
+
+        if !Path::new(&backup).exists() {
+            // A hard link is free, and the next save replaces the path with a
+            // new file rather than writing to the linked one.
+            if std::fs::hard_link(&self.path, &backup).is_err() {
+                std::fs::copy(&self.path, &backup)?;
+            }
+        }
+
+        Ok(backup)
+    }
+
+    fn backup_due(&self) -> ah::Result<bool> {
+        let latest = self
+            .backups_on_disk()?
+            .iter()
+            .filter_map(|backup| backup_time(backup))
+            .max();
+
+        Ok(latest.is_none_or(|latest| {
+            Utc::now() - latest >= chrono::Duration::hours(BACKUP_INTERVAL_HOURS)
+        }))
+    }
+
+    fn rotate_backups(&self) -> ah::Result<()> {
+        let mut backups = self.backups_on_disk()?;
+        backups.sort();
+
+        let excess = backups.len().saturating_sub(self.backups);
+
+        for backup in &backups[..excess] {
+            std::fs::remove_file(backup)?;
+        }
+
+        Ok(())
+    }
+
+    /// The timestamped backups of this file, by path.
+    fn backups_on_disk(&self) -> ah::Result<Vec<String>> {
+        let path = Path::new(&self.path);
+        let dir = match path.parent() {
+            Some(parent) if !parent.as_os_str().is_empty() => parent,
+            _ => Path::new("."),
+        };
+
+        let prefix = match path.file_name().and_then(|name| name.to_str()) {
+            Some(name) => format!("{}.", name),
+            None => return Ok(Vec::new()),
+        };
+
+        let mut backups = Vec::new();
+
+        for entry in std::fs::read_dir(dir)? {
+            let entry = entry?;
+            let name = entry.file_name();
+
+            let timestamp = name
+                .to_str()
+                .and_then(|name| name.strip_prefix(&prefix))
+                .and_then(|name| name.strip_suffix(".bak"));
+
+            let is_backup = timestamp.is_some_and(|timestamp| {
+                chrono::NaiveDateTime::parse_from_str(timestamp, BACKUP_TIMESTAMP).is_ok()
+            });
+
+            if is_backup {
+                backups.push(entry.path().to_string_lossy().into_owned());
+            }
+        }
+
+        Ok(backups)
+    }
+}
+
+/// When a backup was taken, going by the timestamp in its path.
+fn backup_time(backup: &str) -> Option<DateTime<Utc>> {
+    let timestamp = backup.strip_suffix(".bak")?.rsplit('.').next()?;
+    let time = chrono::NaiveDateTime::parse_from_str(timestamp, BACKUP_TIMESTAMP).ok()?;
+    Some(time.and_utc())
+}
+
+/// Replaces a file without ever leaving it half written: the data is written
+/// to a temporary file next to it first, synced to disk, and only then renamed
+/// over the file, so that a crash or a full disk leaves the old version in
+/// place rather than a truncated one.
+pub fn write_atomically(path: &str, data: &[u8]) -> ah::Result<()> {
+    let target = Path::new(path);
+
+    let name = target
+        .file_name()
+        .and_then(|name| name.to_str())
+        .ok_or_else(|| ah::anyhow!("Not a file path: {}", path))?;
+
+    let temporary = target.with_file_name(format!(".{}.tmp", name));
+
+    let result = (|| -> std::io::Result<()> {
+        let mut file = File::create(&temporary)?;
+        file.write_all(data)?;
+        file.sync_all()?;
+        std::fs::rename(&temporary, target)
+    })();
+
+    if let Err(error) = result {
+        let _ = std::fs::remove_file(&temporary);
+        ah::bail!("Failed to write {}: {}", path, error);
+    }
+
+    // The rename only survives a crash once the directory is synced too. Not
+    // every platform can open a directory for that, so it's best effort.
+    if let Some(parent) = target.parent().filter(|p| !p.as_os_str().is_empty()) {
+        if let Ok(dir) = File::open(parent) {
+            let _ = dir.sync_all();
+        }
+    }
+
+    Ok(())
+}
+
+/// A report stored as JSON, as it always has been.
+#[derive(Debug)]
+pub struct JsonFile {
+    pub file: ReportFile,
+    pub encryption: Option<Encryption>,
+}
+
+impl Storage for JsonFile {
+    fn load(&mut self) -> ah::Result<Option<RepositoryReport>> {
+        if !self.file.exists() {
+            return Ok(None);
+        }
+
+        let passphrase = self.encryption.as_ref().map(|e| &e.passphrase);
+        RepositoryReport::load(&self.file.path, passphrase).map(Some)
+    }
+
+    fn upsert_snapshot(&mut self, report: &RepositoryReport) -> ah::Result<()> {
+        self.file.prepare_write()?;
+        report.save(&self.file.path, self.encryption.as_ref())
+    }
+
+    fn lock(&mut self) -> ah::Result<()> {
+        self.file.lock()
+    }
+
+    fn reencrypt(&mut self, encryption: &Encryption) -> ah::Result<Option<Vec<u8>>> {
+        reencrypt_file(&mut self.file, self.encryption.as_ref(), encryption)
+    }
+
+    fn write_reencrypted(
+        &mut self,
+        data: Option<Vec<u8>>,
+        encryption: Encryption,
+    ) -> ah::Result<()> {
+        if let Some(data) = data {
+            self.file.write(&data)?;
+        }
+
+        self.encryption = Some(encryption);
+        Ok(())
+    }
+
+    fn backup(&mut self) -> ah::Result<Option<String>> {
+        self.file.lock()?;
+
+        match self.file.exists() {
+            true => self.file.link_backup().map(Some),
+            false => Ok(None),
+        }
+    }
+
+    fn location(&self) -> String {
+        self.file.path.clone()
+    }
+}
+
+/// A report stored as gzipped JSON, which is encrypted after compression. A
+/// report that was kept as plain JSON at report_path before is imported until
+/// the compressed file exists, see import_json_report.
+#[derive(Debug)]
+pub struct CompressedJsonFile {
+    pub file: ReportFile,
+    pub encryption: Option<Encryption>,
+    pub report_path: String,
+}
+
+impl CompressedJsonFile {
+    /// The compressed file of a report that would otherwise be at the path.
+    pub fn for_report(report_path: &str, backups: usize, encryption: Option<Encryption>) -> Self {
+        CompressedJsonFile {
+            file: ReportFile::new(&format!("{}.gz", report_path), backups),
+            encryption,
+            report_path: report_path.into(),
+        }
+    }
+}
+
+impl Storage for CompressedJsonFile {
+    fn load(&mut self) -> ah::Result<Option<RepositoryReport>> {
+        if !self.file.exists() {
+            return import_json_report(&self.report_path, self.encryption.as_ref());
+        }
+
+        let mut data = std::fs::read(&self.file.path)?;
+
+        if is_encrypted(&data) {
+            let Some(encryption) = &self.encryption else {
+                ah::bail!("The report is encrypted, but no passphrase was given");
+            };
+
+            data = decrypt_with_passphrase(&data, &encryption.passphrase.0)?;
+        }
+
+        let mut json = Vec::new();
+        GzDecoder::new(&data[..]).read_to_end(&mut json)?;
+        RepositoryReport::from_json(&json).map(Some)
+    }
+
+    fn upsert_snapshot(&mut self, report: &RepositoryReport) -> ah::Result<()> {
+        let mut encoder = GzEncoder::new(Vec::new(), Compression::default());
+        encoder.write_all(&report.to_json()?)?;
+        let mut data = encoder.finish()?;
+
+        if let Some(encryption) = &self.encryption {
+            data = encrypt_with_passphrase(&data, &encryption.passphrase.0, encryption.kdf)?;
+        }
+
+        self.file.write(&data)
+    }
+
+    fn lock(&mut self) -> ah::Result<()> {
+        self.file.lock()
+    }
+
+    fn reencrypt(&mut self, encryption: &Encryption) -> ah::Result<Option<Vec<u8>>> {
+        reencrypt_file(&mut self.file, self.encryption.as_ref(), encryption)
+    }
+
+    fn write_reencrypted(
+        &mut self,
+        data: Option<Vec<u8>>,
+        encryption: Encryption,
+    ) -> ah::Result<()> {
+        if let Some(data) = data {
+            self.file.write(&data)?;
+        }
+
+        self.encryption = Some(encryption);
+        Ok(())
+    }
+
+    fn backup(&mut self) -> ah::Result<Option<String>> {
+        self.file.lock()?;
+
+        match self.file.exists() {
+            true => self.file.link_backup().map(Some),
+            false => Ok(None),
+        }
+    }
+
+    fn location(&self) -> String {
+        self.file.path.clone()
+    }
+}
+
+/// A report stored in the database shared by every repository, under its
+/// full name. A report that was kept as JSON at report_path before is imported
+/// while the database has none yet, see import_json_report.
+#[derive(Debug)]
+pub struct DatabaseStorage {
+    pub database: SharedDatabase,
+    pub repository: String,
+    pub report_path: String,
+}
+
+impl DatabaseStorage {
+    fn lock_database(&self) -> ah::Result<std::sync::MutexGuard<'_, Database>> {
+        self.database
+            .lock()
+            .map_err(|_| ah::anyhow!("The database was poisoned by a panic"))
+    }
+}
+
+impl Storage for DatabaseStorage {
+    fn load(&mut self) -> ah::Result<Option<RepositoryReport>> {
+        match self.lock_database()?.load(&self.repository)? {
+            Some(report) => Ok(Some(report)),
+            None => import_json_report(&self.report_path, None),
+        }
+    }
+
+    fn upsert_snapshot(&mut self, report: &RepositoryReport) -> ah::Result<()> {
+        let mut database = self.lock_database()?;
+        database.lock()?;
+        database.save(&self.repository, report)?;
+        Ok(())
+    }
+
+    fn lock(&mut self) -> ah::Result<()> {
+        self.lock_database()?.lock()
+    }
+
+    fn query_range(
+        &mut self,
+        from: DateTime<Utc>,
+        to: DateTime<Utc>,
+    ) -> ah::Result<Option<RepositoryReport>> {
+        self.lock_database()?
+            .query_range(&self.repository, from, to)
+    }
+
+    fn reencrypt(&mut self, _: &Encryption) -> ah::Result<Option<Vec<u8>>> {
+        ah::bail!("Reports in the database can't be encrypted")
+    }
+
+    fn write_reencrypted(&mut self, _: Option<Vec<u8>>, _: Encryption) -> ah::Result<()> {
+        ah::bail!("Reports in the database can't be encrypted")
+    }
+
+    fn location(&self) -> String {
+        format!("{} in the database", self.repository)
+    }
+}
+
+/// The report kept as JSON at the path before another storage was configured,
+/// if there is one, which that storage loads for as long as it has none of its
+/// own. Starting over instead would lose all of the history the API no longer
+/// has, and the report is stored by the next save anyway.
+fn import_json_report(
+    path: &str,
+    encryption: Option<&Encryption>,
+) -> ah::Result<Option<RepositoryReport>> {
+    if !Path::new(path).exists() {
+        return Ok(None);
+    }
+
+    log::info!("Importing the existing report {}", path);
+
+    let passphrase = encryption.map(|e| &e.passphrase);
+    RepositoryReport::load(path, passphrase).map(Some)
+}
+
+fn create_parent_dir(path: &str) -> ah::Result<()> {
+    if let Some(parent) = Path::new(path).parent() {
+        std::fs::create_dir_all(parent)?;
+    }
+
+    Ok(())
+}
+
+/// Locks the file, and re-encrypts it in memory, see RepositoryReport::reencrypt.
+fn reencrypt_file(
+    file: &mut ReportFile,
+    current: Option<&Encryption>,
+    encryption: &Encryption,
+) -> ah::Result<Option<Vec<u8>>> {
+    if !file.exists() {
+        return Ok(None);
+    }
+
+    file.lock()?;
+    let data = std::fs::read(&file.path)?;
+    let passphrase = current.map(|e| &e.passphrase);
+    RepositoryReport::reencrypt(&data, passphrase, encryption)
+}
+
+/// The storage selected in the configuration, from which the storage of each
+/// repository is opened. The database, if any, is opened once and shared.
+#[derive(Debug, Clone)]
+pub struct StorageBackend {
+    pub kind: StorageKind,
+    pub encryption: Option<Encryption>,
+
+    /// How many backups of each report file to keep.
+    pub backups: usize,
+
+    database: Option<SharedDatabase>,
+}
+
+impl StorageBackend {
+    pub fn new(config: &Config) -> ah::Result<Self> {
+        let kind = config.storage();
+
+        let database = match kind {
+            StorageKind::Database => Some(Database::open_shared(&config.database_path())?),
+            _ => None,
+        };
+
+        Ok(StorageBackend {
+            kind,
+            encryption: config.encryption()?,
+            backups: config.backups,
+            database,
+        })
+    }
+
+    pub fn open(&self, target: &RepositoryTarget) -> Box<dyn Storage> {
+        let encryption = self.encryption.clone();
+
+        match (self.kind, &self.database) {
+            (StorageKind::Database, Some(database)) => Box::new(DatabaseStorage {
+                database: database.clone(),
+                repository: target.name(),
+                report_path: target.report_path.clone(),
+            }),
+            (StorageKind::CompressedJson, _) => Box::new(CompressedJsonFile::for_report(
+                &target.report_path,
+                self.backups,
+                encryption,
+            )),
+            _ => Box::new(JsonFile {
+                file: ReportFile::new(&target.report_path, self.backups),
+                encryption,
+            }),
+        }
+    }
+}
+
+/// The --change-passphrase option: re-encrypts every listed and discovered
+/// report under a new passphrase, or encrypts it if it's still plain. Every
+/// report is re-encrypted in memory before any of them is written, so that one
+/// that can't be leaves all of them as they were, and reports that are already
+/// under the new passphrase are skipped, so that a change cut short while
+/// writing can be retried with the same settings.
+pub fn reencrypt_reports(config: &Config, passphrase: Passphrase) -> ah::Result<()> {
+    let backend = StorageBackend::new(config)?;
+    let mut targets = config.targets();
+
+    // A report that was left out would be stuck under the old passphrase.
+    targets.extend(discover_targets(config)?);
+
+    let encryption = Encryption {
+        passphrase,
+        kdf: config.kdf,
+    };
+
+    let mut reencrypted = Vec::with_capacity(targets.len());
+
+    for target in &targets {
+        let mut storage = backend.open(target);
+
+        let data = storage.reencrypt(&encryption).map_err(|e| {
+            ah::anyhow!(
+                "Failed to change the passphrase of {}, no report was changed: {}",
+                storage.location(),
+                e
+            )
+        })?;
+
+        reencrypted.push((storage, data));
+    }
+
+    for (mut storage, data) in reencrypted {
+        let changed = data.is_some();
+
+        storage
+            .write_reencrypted(data, encryption.clone())
+            .map_err(|e| {
+                ah::anyhow!(
+                    "Failed to change the passphrase of {}, run again to finish: {}",
+                    storage.location(),
+                    e
+                )
+            })?;
+
+        if changed {
+            log::info!("Changed the passphrase of {}", storage.location());
+        }
+    }
+
+    Ok(())
+}
+
+/// The range command: prints what happened to every listed and discovered
+/// repository from one time until before the other, as a JSON object keyed by
+/// their full names, leaving out those without a report.
+pub fn print_range(config: &Config, from: DateTime<Utc>, to: DateTime<Utc>) -> ah::Result<()> {
+    let backend = StorageBackend::new(config)?;
+    let mut targets = config.targets();
+
+    match discover_targets(config) {
+        Ok(discovered) => targets.extend(discovered),
+        Err(error) => log::warn!("{}", error),
+    }
+
+    let mut reports = BTreeMap::new();
+
+    for target in &targets {
+        if let Some(report) = backend.open(target).query_range(from, to)? {
+            reports.insert(target.name(), report);
+        }
+    }
+
+    println!("{}", serde_json::to_string_pretty(&reports)?);
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::TimeZone;
+
+    fn report() -> RepositoryReport {
+        let mut report = RepositoryReport::default();
+
+        for (hour, amount) in [(0, 5), (1, 7), (2, 11)] {
+            report.hourly_views.insert(
+                Utc.with_ymd_and_hms(2024, 1, 1, hour, 0, 0).unwrap(),
+                QuantifiableEvents {
+                    amount,
+                    amount_unique: 1,
+                },
+            );
+        }
+
+        report
+            .stargazers
+            .insert("octocat".into(), hour(1) + chrono::Duration::minutes(30));
+
+        report
+    }
+
+    fn hour(hour: u32) -> DateTime<Utc> {
+        Utc.with_ymd_and_hms(2024, 1, 1, hour, 0, 0).unwrap()
+    }
+
+    #[test]
+    fn test_storages_round_trip() {
+        let dir = std::env::temp_dir().join(format!("storage-{}", std::process::id()));
+        let report_path = dir.join("owner-repo.json");
+        let report_path = report_path.to_str().unwrap();
+
+        // The JSON file is last, as the others would import it.
+        let storages: Vec<Box<dyn Storage>> = vec![
+            Box::new(CompressedJsonFile::for_report(report_path, 0, None)),
+            Box::new(DatabaseStorage {
+                database: Database::open_shared(":memory:").unwrap(),
+                repository: "owner/repo".into(),
+                report_path: report_path.into(),
+            }),
+            Box::new(JsonFile {
+                file: ReportFile::new(report_path, 0),
+                encryption: None,
+            }),
+        ];
+
+        for mut storage in storages {
+            assert!(storage.load().unwrap().is_none());
+            storage.upsert_snapshot(&report()).unwrap();
+
+            let report = storage.load().unwrap().unwrap();
+            assert_eq!(report.hourly_views.len(), 3);
+
+            let range = storage.query_range(hour(1), hour(2)).unwrap().unwrap();
+            assert_eq!(range.hourly_views.len(), 1, "{}", storage.location());
+            assert_eq!(range.hourly_views[&hour(1)].amount, 7);
+            assert_eq!(range.stargazers.len(), 1);
+        }
+
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn test_compressed_json_imports_the_json_report() {
+        let dir = std::env::temp_dir().join(format!("import-gz-{}", std::process::id()));
+        let report_path = dir.join("owner-repo.json");
+        let report_path = report_path.to_str().unwrap();
+
+        let mut json = JsonFile {
+            file: ReportFile::new(report_path, 0),
+            encryption: None,
+        };
+        json.upsert_snapshot(&report()).unwrap();
+
+        let mut compressed = CompressedJsonFile::for_report(report_path, 0, None);
+        let mut imported = compressed.load().unwrap().unwrap();
+        assert_eq!(imported.hourly_views.len(), 3);
+
+        imported.hourly_views.clear();
+        compressed.upsert_snapshot(&imported).unwrap();
+        assert!(compressed.load().unwrap().unwrap().hourly_views.is_empty());
+
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn test_database_imports_the_json_report() {
+        let dir = std::env::temp_dir().join(format!("import-{}", std::process::id()));
+        let report_path = dir.join("owner-repo.json");
+        let report_path = report_path.to_str().unwrap();
+
+        let mut json = JsonFile {
+            file: ReportFile::new(report_path, 0),
+            encryption: None,
+        };
+        json.upsert_snapshot(&report()).unwrap();
+
+        let mut database = DatabaseStorage {
+            database: Database::open_shared(":memory:").unwrap(),
+            repository: "owner/repo".into(),
+            report_path: report_path.into(),
+        };
+
+        let mut imported = database.load().unwrap().unwrap();
+        assert_eq!(imported.hourly_views.len(), 3);
+
+        // Once the database has a report of its own, the file is left alone.
+        imported.hourly_views.clear();
+        database.upsert_snapshot(&imported).unwrap();
+        assert!(database.load().unwrap().unwrap().hourly_views.is_empty());
+
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn test_report_file_backups_and_lock() {
+        let dir = std::env::temp_dir().join(format!("report-file-{}", std::process::id()));
+        let path = dir.join("owner-repo.json");
+        let path = path.to_str().unwrap();
+
+        let mut file = ReportFile::new(path, 2);
+        file.write(b"first").unwrap();
+        assert!(file.backups_on_disk().unwrap().is_empty());
+
+        // Older backups than the two latest are rotated out, other files aren't.
+        for name in ["20240101T000000Z", "20240102T000000Z"] {
+            std::fs::write(format!("{}.{}.bak", path, name), name).unwrap();
+        }
+
+        std::fs::write(format!("{}.bak", path), "rebuilt").unwrap();
+        file.write(b"second").unwrap();
+
+        let mut backups = file.backups_on_disk().unwrap();
+        backups.sort();
+
+        assert_eq!(backups.len(), 2);
+        assert!(backups[0].ends_with(".20240102T000000Z.bak"));
+        assert_eq!(std::fs::read(&backups[1]).unwrap(), b"first");
+        assert_eq!(std::fs::read(path).unwrap(), b"second");
+        assert!(Path::new(&format!("{}.bak", path)).exists());
+        assert!(!Path::new(&dir.join(".owner-repo.json.tmp")).exists());
+
+        let mut other = ReportFile::new(path, 2);
+        assert!(other.write(b"third").is_err());
+        assert_eq!(std::fs::read(path).unwrap(), b"second");
+
+        drop(file);
+        other.write(b"third").unwrap();
+
+        // The latest backup is less than a day old, so there's no new one.
+        let mut backups = other.backups_on_disk().unwrap();
+        backups.sort();
+
+        assert_eq!(backups.len(), 2);
+        assert_eq!(std::fs::read(&backups[1]).unwrap(), b"first");
+
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn test_reencrypt_can_be_retried() {
+        let dir = std::env::temp_dir().join(format!("reencrypt-{}", std::process::id()));
+        let path = dir.join("owner-repo.json");
+        let path = path.to_str().unwrap();
+
+        let encryption = |passphrase: &str| Encryption {
+            passphrase: Passphrase(passphrase.into()),
+            kdf: KdfParams {
+                mem_cost: 64,
+                time_cost: 1,
+                lanes: 1,
+            },
+        };
+
+        let open = |passphrase: &str| JsonFile {
+            file: ReportFile::new(path, 0),
+            encryption: Some(encryption(passphrase)),
+        };
+
+        open("old").upsert_snapshot(&report()).unwrap();
+
+        // Nothing is written until write_reencrypted is.
+        let mut storage = open("old");
+        let data = storage.reencrypt(&encryption("new")).unwrap();
+        assert!(data.is_some());
+        assert!(open("old").load().is_ok());
+
+        storage.write_reencrypted(data, encryption("new")).unwrap();
+        drop(storage);
+
+        // A retry with the old settings skips what was already re-encrypted.
+        assert!(open("old").reencrypt(&encryption("new")).unwrap().is_none());
+        assert!(open("wrong").reencrypt(&encryption("other")).is_err());
+        assert_eq!(open("new").load().unwrap().unwrap().hourly_views.len(), 3);
+
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+}
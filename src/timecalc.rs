@@ -2,32 +2,41 @@ use anyhow as ah;
 use chrono::{prelude::*, Duration};
 use std::time::{SystemTime, UNIX_EPOCH};
 
-pub fn get_utc_datestamp() -> ah::Result<String> {
-    let utc_now = Utc::now();
-
-    let midnight_aligned = Utc
-        .with_ymd_and_hms(utc_now.year(), utc_now.month(), utc_now.day(), 0, 0, 0)
-        .single();
-
-    midnight_aligned
-        .ok_or_else(|| {
-            ah::anyhow!(format!(
-                "Failed to parse timestamp {}, option was None",
-                utc_now
-            ))
-        })
-        .map(|time| time.format("%Y-%m-%dT%H:%M:%SZ").to_string())
+/// Formats a time the way the API formats its timestamps, which sort the same
+/// as the times they stand for.
+pub fn format_timestamp(time: &DateTime<Utc>) -> String {
+    time.format("%Y-%m-%dT%H:%M:%SZ").to_string()
+}
+
+/// Parses either an RFC 3339 timestamp, or a date, taken as the midnight that
+/// starts it in UTC.
+pub fn parse_time(time: &str) -> ah::Result<DateTime<Utc>> {
+    if let Ok(time) = DateTime::parse_from_rfc3339(time) {
+        return Ok(time.with_timezone(&Utc));
+    }
+
+    let date = NaiveDate::parse_from_str(time, "%Y-%m-%d")
+        .map_err(|_| ah::anyhow!("Expected a date or RFC 3339 timestamp, got {}", time))?;
+
+    Ok(date.and_time(NaiveTime::MIN).and_utc())
+}
+
+/// The midnight starting the UTC day of the given time.
+pub fn utc_datestamp(time: &DateTime<Utc>) -> ah::Result<DateTime<Utc>> {
+    time.date_naive()
+        .and_hms_opt(0, 0, 0)
+        .map(|midnight| midnight.and_utc())
+        .ok_or_else(|| ah::anyhow!("Failed to align {} to midnight", time))
 }
 
 pub fn next_utc_day() -> ah::Result<Duration> {
     let now = Utc::now();
 
-    match Utc.with_ymd_and_hms(now.year(), now.month(), now.day() + 1, 0, 0, 0) {
-        chrono::LocalResult::None => ah::bail!(""),
-        chrono::LocalResult::Single(r) => Ok(r),
-        chrono::LocalResult::Ambiguous(r1, _) => Ok(r1),
-    }
-    .map(|t| t.signed_duration_since(now))
+    now.date_naive()
+        .succ_opt()
+        .and_then(|tomorrow| tomorrow.and_hms_opt(0, 0, 0))
+        .map(|midnight| midnight.and_utc().signed_duration_since(now))
+        .ok_or_else(|| ah::anyhow!("Failed to calculate the next UTC midnight after {}", now))
 }
 
 pub fn get_unix_timestamp() -> u64 {
@@ -38,15 +47,8 @@ pub fn get_unix_timestamp() -> u64 {
     duration_since_epoch.as_secs()
 }
 
-pub fn subtract_two_weeks(timestamp: &String) -> ah::Result<String> {
-    DateTime::parse_from_rfc3339(timestamp).map(|time| {
-        time.with_day(time.with_timezone(&Utc).day() - 14)
-            .map(|past| past.format("%Y-%m-%dT%H:%M:%SZ").to_string())
-            .ok_or_else(|| {
-                ah::anyhow!(format!(
-                    "Failed to parse timestamp {}, option was None",
-                    timestamp
-                ))
-            })
-    })?
+pub fn subtract_two_weeks(timestamp: &DateTime<Utc>) -> ah::Result<DateTime<Utc>> {
+    timestamp
+        .checked_sub_signed(Duration::days(14))
+        .ok_or_else(|| ah::anyhow!("Failed to subtract two weeks from timestamp {}", timestamp))
 }
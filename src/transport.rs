@@ -0,0 +1,239 @@
+use std::collections::HashMap;
+use std::fmt::Debug;
+
+use crate::api_error::*;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Method {
+    Get,
+    Post,
+}
+
+impl std::fmt::Display for Method {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Method::Get => write!(f, "GET"),
+            Method::Post => write!(f, "POST"),
+        }
+    }
+}
+
+/// A request without a body, as the Client sends it.
+#[derive(Debug, Clone)]
+pub struct Request {
+    pub method: Method,
+    pub url: String,
+    pub headers: Vec<(&'static str, String)>,
+
+    /// Seconds to wait for a response before giving up.
+    pub timeout: u64,
+}
+
+/// A response of any status, which is up to the Client to interpret.
+#[derive(Debug, Clone, Default)]
+pub struct Response {
+    pub status_code: i32,
+    pub reason_phrase: String,
+    pub headers: HashMap<String, String>,
+    pub body: Vec<u8>,
+}
+
+impl Response {
+    pub fn as_str(&self) -> Result<&str, std::str::Utf8Error> {
+        std::str::from_utf8(&self.body)
+    }
+}
+
+impl From<minreq::Response> for Response {
+    fn from(response: minreq::Response) -> Self {
+        Response {
+            body: response.as_bytes().to_vec(),
+            status_code: response.status_code,
+            reason_phrase: response.reason_phrase,
+            headers: response.headers,
+        }
+    }
+}
+
+/// How requests reach the API. Only fails if there's no response at all.
+pub trait Transport: Debug + Send + Sync {
+    fn send(&self, request: &Request) -> ApiResult<Response>;
+}
+
+/// Sends requests over the network.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct MinreqTransport;
+
+impl Transport for MinreqTransport {
+    fn send(&self, request: &Request) -> ApiResult<Response> {
+        let method = match request.method {
+            Method::Get => minreq::Method::Get,
+            Method::Post => minreq::Method::Post,
+        };
+
+        let response = request
+            .headers
+            .iter()
+            .fold(
+                minreq::Request::new(method, &request.url),
+                |builder, (name, value)| builder.with_header(*name, value),
+            )
+            .with_timeout(request.timeout)
+            .send()
+            .map_err(|e| ApiError::network(&request.url, e))?;
+
+        Ok(response.into())
+    }
+}
+
+#[cfg(test)]
+pub use replay::*;
+
+#[cfg(test)]
+mod replay {
+    use serde::Deserialize;
+    use std::collections::{HashMap, VecDeque};
+    use std::path::Path;
+    use std::sync::Mutex;
+
+    use super::*;
+
+    /// A recorded response to a request for the URL.
+    #[derive(Debug, Clone, Deserialize)]
+    #[serde(deny_unknown_fields)]
+    pub struct Fixture {
+        pub url: String,
+
+        #[serde(default = "default_status")]
+        pub status: i32,
+
+        #[serde(default)]
+        pub headers: HashMap<String, String>,
+
+        #[serde(default)]
+        pub body: serde_json::Value,
+    }
+
+    fn default_status() -> i32 {
+        200
+    }
+
+    /// Serves recorded fixtures by URL instead of sending requests, to test the
+    /// client without network access. A URL with several fixtures is answered
+    /// with each of them in turn, repeating the last one. Like the API, it
+    /// answers 304 to an If-None-Match that matches the ETag of the fixture.
+    #[derive(Debug, Default)]
+    pub struct ReplayTransport {
+        fixtures: Mutex<HashMap<String, VecDeque<Fixture>>>,
+        requests: Mutex<Vec<Request>>,
+    }
+
+    impl ReplayTransport {
+        /// Loads every fixture in a directory of JSON files, each of which
+        /// holds either a single fixture, or an array of them.
+        pub fn load(dir: impl AsRef<Path>) -> anyhow::Result<Self> {
+            let replay = ReplayTransport::default();
+            let mut paths: Vec<_> = std::fs::read_dir(dir)?
+                .map(|entry| entry.map(|entry| entry.path()))
+                .collect::<Result<_, _>>()?;
+
+            paths.sort();
+
+            for path in paths
+                .iter()
+                .filter(|p| p.extension() == Some("json".as_ref()))
+            {
+                let json: serde_json::Value = serde_json::from_slice(&std::fs::read(path)?)?;
+
+                let fixtures: Vec<Fixture> = match json {
+                    serde_json::Value::Array(_) => serde_json::from_value(json)?,
+                    json => vec![serde_json::from_value(json)?],
+                };
+
+                fixtures.into_iter().for_each(|fixture| replay.add(fixture));
+            }
+
+            Ok(replay)
+        }
+
+        pub fn add(&self, fixture: Fixture) {
+            if let Ok(mut fixtures) = self.fixtures.lock() {
+                fixtures
+                    .entry(fixture.url.clone())
+                    .or_default()
+                    .push_back(fixture);
+            }
+        }
+
+        /// Replaces the fixtures of the URL, e.g. to have data change between
+        /// two collections.
+        pub fn replace(&self, fixture: Fixture) {
+            if let Ok(mut fixtures) = self.fixtures.lock() {
+                fixtures.insert(fixture.url.clone(), VecDeque::from([fixture]));
+            }
+        }
+
+        /// The URLs requested so far, in order.
+        pub fn requests(&self) -> Vec<String> {
+            self.sent().into_iter().map(|request| request.url).collect()
+        }
+
+        /// The requests sent so far, in order.
+        pub fn sent(&self) -> Vec<Request> {
+            self.requests.lock().map(|r| r.clone()).unwrap_or_default()
+        }
+    }
+
+    impl Transport for ReplayTransport {
+        fn send(&self, request: &Request) -> ApiResult<Response> {
+            if let Ok(mut requests) = self.requests.lock() {
+                requests.push(request.clone());
+            }
+
+            let fixture = self.fixtures.lock().ok().and_then(|mut fixtures| {
+                let queue = fixtures.get_mut(&request.url)?;
+
+                match queue.len() {
+                    1 => queue.front().cloned(),
+                    _ => queue.pop_front(),
+                }
+            });
+
+            let Some(fixture) = fixture else {
+                return Err(ApiError::Network {
+                    url: request.url.clone(),
+                    message: "No fixture was recorded for the URL".into(),
+                });
+            };
+
+            let etag = fixture.headers.get("ETag");
+
+            let if_none_match = request
+                .headers
+                .iter()
+                .find(|(name, _)| name.eq_ignore_ascii_case("If-None-Match"))
+                .map(|(_, value)| value);
+
+            if etag.is_some() && etag == if_none_match {
+                return Ok(Response {
+                    status_code: 304,
+                    reason_phrase: "Not Modified".into(),
+                    headers: fixture.headers,
+                    body: Vec::new(),
+                });
+            }
+
+            let body = match fixture.body {
+                serde_json::Value::String(body) => body.into_bytes(),
+                body => serde_json::to_vec(&body).unwrap_or_default(),
+            };
+
+            Ok(Response {
+                status_code: fixture.status,
+                reason_phrase: String::new(),
+                headers: fixture.headers,
+                body,
+            })
+        }
+    }
+}
@@ -0,0 +1,339 @@
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::io::{BufRead, IsTerminal};
+use std::path::Path;
+use std::sync::RwLock;
+
+use crate::api::*;
+use crate::crypto::*;
+use crate::storage::*;
+
+use anyhow as ah;
+
+pub const VAULT_FILE: &str = "./tokens.vault";
+
+const HELP_TEXT: &str = "
+Usage: github-chronicler vault <add|remove> <name> [--vault <path>] [--vault-fd <fd>]
+       github-chronicler vault list [--vault <path>] [--vault-fd <fd>]
+
+Keeps tokens encrypted under a passphrase, for repositories to refer to by name
+with vault_token or --vault-token. add reads the token from stdin, and creates
+the vault if it doesn't exist yet.
+
+Options:
+  -v, --vault <path>        The token vault. (./tokens.vault)
+  -f, --vault-fd <fd>       Read the vault passphrase from this file descriptor
+                            rather than from $CHRONICLER_VAULT_PASSPHRASE, or
+                            a prompt.
+  -h, --help                Print this help text.";
+
+/// The environment variable the master passphrase is read from, when it's not
+/// given through a file descriptor.
+pub const PASSPHRASE_VARIABLE: &str = "CHRONICLER_VAULT_PASSPHRASE";
+
+/// Named tokens, stored encrypted under a master passphrase in the same format
+/// as encrypted reports, so that tokens don't have to sit in plain files.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Vault {
+    tokens: BTreeMap<String, String>,
+}
+
+impl Vault {
+    pub fn open(path: &str, passphrase: &Passphrase) -> ah::Result<Self> {
+        let data = std::fs::read(path)
+            .map_err(|e| ah::anyhow!("Failed to read the vault {}: {}", path, e))?;
+
+        let plaintext = decrypt_with_passphrase(&data, &passphrase.0)
+            .map_err(|e| ah::anyhow!("Failed to unlock the vault {}: {}", path, e))?;
+
+        Ok(serde_json::from_slice(&plaintext)?)
+    }
+
+    pub fn save(&self, path: &str, passphrase: &Passphrase) -> ah::Result<()> {
+        let plaintext = serde_json::to_vec(self)?;
+        let data = encrypt_with_passphrase(&plaintext, &passphrase.0, KdfParams::default())?;
+
+        write_atomically(path, &data)
+            .map_err(|e| ah::anyhow!("Failed to save the vault {}: {}", path, e))
+    }
+
+    /// Adds a token, returning whether it replaced one of the same name.
+    pub fn insert(&mut self, name: &str, token: AuthToken) -> bool {
+        self.tokens.insert(name.into(), token.0).is_some()
+    }
+
+    pub fn remove(&mut self, name: &str) -> bool {
+        self.tokens.remove(name).is_some()
+    }
+
+    pub fn names(&self) -> impl Iterator<Item = &String> {
+        self.tokens.keys()
+    }
+
+    pub fn token(&self, name: &str) -> Option<AuthToken> {
+        self.tokens.get(name).cloned().map(AuthToken)
+    }
+}
+
+/// Reads the master passphrase, from the file descriptor if one is given,
+/// otherwise from PASSPHRASE_VARIABLE, otherwise by prompting for it if there
+/// is a terminal to prompt on. A new passphrase is prompted for twice.
+pub fn read_master_passphrase(fd: Option<i32>, new: bool) -> ah::Result<Passphrase> {
+    let passphrase = if let Some(fd) = fd {
+        read_fd_line(fd)?
+    } else if let Ok(passphrase) = std::env::var(PASSPHRASE_VARIABLE) {
+        passphrase
+    } else if std::io::stdin().is_terminal() {
+        let passphrase = rpassword::prompt_password("Vault passphrase: ")?;
+
+        if new && rpassword::prompt_password("Repeat the passphrase: ")? != passphrase {
+            ah::bail!("The passphrases don't match");
+        }
+
+        passphrase
+    } else {
+        ah::bail!(
+            "The vault passphrase is needed, but there's no terminal to prompt for it on, pass it with --vault-fd or {} instead",
+            PASSPHRASE_VARIABLE
+        );
+    };
+
+    if passphrase.is_empty() {
+        ah::bail!("The vault passphrase is empty");
+    }
+
+    Ok(Passphrase(passphrase))
+}
+
+/// Reads the first line from an inherited file descriptor, e.g. one set up
+/// by the shell with 3<passphrase.secret, or a pipe from a secret manager.
+#[cfg(unix)]
+fn read_fd_line(fd: i32) -> ah::Result<String> {
+    use std::os::fd::FromRawFd;
+
+    if fd < 0 {
+        ah::bail!("Invalid file descriptor {}", fd);
+    }
+
+    // SAFETY: the descriptor was passed to us to read the passphrase from, and
+    // isn't used for anything else, so it's fine to take ownership and close it.
+    let file = unsafe { std::fs::File::from_raw_fd(fd) };
+
+    let mut line = String::new();
+    std::io::BufReader::new(file)
+        .read_line(&mut line)
+        .map_err(|e| ah::anyhow!("Failed to read from file descriptor {}: {}", fd, e))?;
+
+    Ok(line.trim_end_matches(['\r', '\n']).to_string())
+}
+
+#[cfg(not(unix))]
+fn read_fd_line(_fd: i32) -> ah::Result<String> {
+    ah::bail!("Reading the passphrase from a file descriptor is only supported on Unix")
+}
+
+/// The vault that tokens are resolved from, once unlocked at startup.
+static UNLOCKED_VAULT: RwLock<Option<Vault>> = RwLock::new(None);
+
+pub fn unlock_vault(path: &str, passphrase: &Passphrase) -> ah::Result<()> {
+    let vault = Vault::open(path, passphrase)?;
+
+    match UNLOCKED_VAULT.write() {
+        Ok(mut unlocked) => *unlocked = Some(vault),
+        Err(_) => ah::bail!("The vault lock is poisoned"),
+    }
+
+    Ok(())
+}
+
+pub fn vault_token(name: &str) -> ah::Result<AuthToken> {
+    let unlocked = UNLOCKED_VAULT
+        .read()
+        .map_err(|_| ah::anyhow!("The vault lock is poisoned"))?;
+
+    let Some(vault) = unlocked.as_ref() else {
+        ah::bail!(
+            "The token {} is in the vault, but the vault isn't unlocked",
+            name
+        );
+    };
+
+    vault
+        .token(name)
+        .ok_or_else(|| ah::anyhow!("There's no token named {} in the vault", name))
+}
+
+pub enum VaultAction {
+    Add(String),
+    List,
+    Remove(String),
+    Help,
+}
+
+/// The vault subcommand: github-chronicler vault <add|list|remove> [name]
+pub struct VaultCommand {
+    pub action: VaultAction,
+    pub path: String,
+    pub fd: Option<i32>,
+}
+
+impl VaultCommand {
+    pub fn parse(mut args: impl Iterator<Item = String>) -> ah::Result<Self> {
+        let mut action = None;
+        let mut name = None;
+        let mut path = VAULT_FILE.to_string();
+        let mut fd = None;
+
+        while let Some(arg) = args.next() {
+            let mut value = || {
+                args.next()
+                    .ok_or_else(|| ah::anyhow!("Missing value for argument {}", arg))
+            };
+
+            match arg.as_str() {
+                "--vault" | "-v" => path = value()?,
+                "--vault-fd" | "-f" => fd = Some(value()?.parse()?),
+                "--help" | "-h" => {
+                    return Ok(VaultCommand {
+                        action: VaultAction::Help,
+                        path,
+                        fd,
+                    })
+                }
+                flag if flag.starts_with('-') => {
+                    ah::bail!("Unknown argument {}, see --help", flag)
+                }
+                _ if action.is_none() => action = Some(arg),
+                _ if name.is_none() => name = Some(arg),
+                unknown => ah::bail!("Unexpected argument {}, see --help", unknown),
+            }
+        }
+
+        let name = || {
+            name.clone()
+                .ok_or_else(|| ah::anyhow!("A token name is required"))
+        };
+
+        let action = match action.as_deref() {
+            Some("add") => VaultAction::Add(name()?),
+            Some("list") => VaultAction::List,
+            Some("remove") => VaultAction::Remove(name()?),
+            Some(unknown) => ah::bail!("Unknown vault action {}, see --help", unknown),
+            None => ah::bail!("A vault action is required, one of add, list or remove"),
+        };
+
+        Ok(VaultCommand { action, path, fd })
+    }
+
+    pub fn run(&self) -> ah::Result<()> {
+        if let VaultAction::Help = self.action {
+            println!("{}", HELP_TEXT);
+            return Ok(());
+        }
+
+        let exists = Path::new(&self.path).exists();
+
+        if !exists && !matches!(self.action, VaultAction::Add(_)) {
+            ah::bail!("There's no vault at {} yet", self.path);
+        }
+
+        let passphrase = read_master_passphrase(self.fd, !exists)?;
+
+        let mut vault = match exists {
+            true => Vault::open(&self.path, &passphrase)?,
+            false => Vault::default(),
+        };
+
+        match &self.action {
+            VaultAction::Add(name) => {
+                let token = read_token_input()?;
+
+                if vault.insert(name, token) {
+                    eprintln!("Replaced the token {}", name);
+                } else {
+                    eprintln!("Added the token {}", name);
+                }
+            }
+            VaultAction::List => {
+                for name in vault.names() {
+                    println!("{}", name);
+                }
+
+                return Ok(());
+            }
+            VaultAction::Remove(name) => {
+                if !vault.remove(name) {
+                    ah::bail!("There's no token named {} in the vault", name);
+                }
+
+                eprintln!("Removed the token {}", name);
+            }
+            VaultAction::Help => return Ok(()),
+        }
+
+        vault.save(&self.path, &passphrase)
+    }
+}
+
+/// Reads the token to add from stdin, so that it doesn't end up in the shell
+/// history or the process list, without echoing it if stdin is a terminal.
+fn read_token_input() -> ah::Result<AuthToken> {
+    let token = if std::io::stdin().is_terminal() {
+        rpassword::prompt_password("Token: ")?
+    } else {
+        let mut line = String::new();
+        std::io::stdin().lock().read_line(&mut line)?;
+        line
+    };
+
+    let token = token.trim();
+
+    if token.is_empty() {
+        ah::bail!("The token is empty");
+    }
+
+    Ok(AuthToken(token.to_string()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_vault_round_trip() {
+        let path = std::env::temp_dir().join(format!("vault-{}.secret", std::process::id()));
+        let path = path.to_str().unwrap();
+        let passphrase = Passphrase("hunter2".into());
+
+        let mut vault = Vault::default();
+        assert!(!vault.insert("personal", AuthToken("ghp_1".into())));
+        assert!(vault.insert("personal", AuthToken("ghp_2".into())));
+        vault.insert("org", AuthToken("ghp_3".into()));
+        vault.save(path, &passphrase).unwrap();
+
+        let wrong = Vault::open(path, &Passphrase("hunter3".into()));
+        let mut vault = Vault::open(path, &passphrase).unwrap();
+        std::fs::remove_file(path).unwrap();
+
+        assert!(wrong.is_err());
+        assert_eq!(vault.token("personal").unwrap().0, "ghp_2");
+        assert!(vault.remove("org"));
+        assert_eq!(vault.names().collect::<Vec<_>>(), vec!["personal"]);
+    }
+
+    #[test]
+    fn test_parse_help() {
+        let parse = |args: &[&str]| VaultCommand::parse(args.iter().map(|arg| arg.to_string()));
+
+        assert!(matches!(
+            parse(&["--help"]).unwrap().action,
+            VaultAction::Help
+        ));
+        assert!(matches!(
+            parse(&["add", "-h"]).unwrap().action,
+            VaultAction::Help
+        ));
+        assert!(parse(&["--bogus"]).is_err());
+    }
+}
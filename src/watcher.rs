@@ -0,0 +1,545 @@
+use chrono::Utc;
+use log::{error, info, warn};
+use std::collections::HashSet;
+use std::time::{Duration, Instant};
+
+use crate::api::*;
+use crate::api_error::*;
+use crate::archive::*;
+use crate::config::*;
+use crate::discovery::*;
+use crate::etag::*;
+use crate::ratelimit::*;
+use crate::report::*;
+use crate::sampler::*;
+use crate::storage::*;
+
+use anyhow as ah;
+
+/// The longest the watcher will wait before retrying after a failed cycle,
+/// regardless of how many failures happened in a row.
+const MAX_RETRY_DELAY: Duration = Duration::from_secs(30 * 60);
+
+/// The delay after the first failed cycle, doubled for every consecutive
+/// failure until either a cycle succeeds or MAX_RETRY_DELAY is reached.
+const BASE_RETRY_DELAY: Duration = Duration::from_secs(30);
+
+/// A single repository being watched, along with its report, if one has been
+/// loaded or created yet, and its own schedule.
+pub struct WatchedRepository {
+    pub target: RepositoryTarget,
+    pub report: Option<RepositoryReport>,
+    storage: Box<dyn Storage>,
+    consecutive_failures: u32,
+    next_cycle: Instant,
+}
+
+impl WatchedRepository {
+    pub fn new(target: RepositoryTarget, storage: Box<dyn Storage>) -> ah::Result<Self> {
+        let mut storage = storage;
+
+        // A report that exists but cannot be loaded is fatal, since carrying on
+        // would mean overwriting it, along with all of the history it contains
+        // that the API can no longer provide.
+        let report = storage.load().map_err(|e| {
+            ah::anyhow!(
+                "Failed to load the existing report {}, refusing to overwrite it: {}",
+                storage.location(),
+                e
+            )
+        })?;
+
+        Ok(WatchedRepository {
+            target,
+            report,
+            storage,
+            consecutive_failures: 0,
+            next_cycle: Instant::now(),
+        })
+    }
+
+    pub fn name(&self) -> String {
+        self.target.name()
+    }
+
+    /// Performs a single collection cycle: requests the latest data, archives
+    /// it if it changed, merges it into the report (or creates it), and saves
+    /// the report to disk.
+    pub fn cycle(&mut self) -> ah::Result<()> {
+        // Taken before anything is requested, as the requests would be wasted
+        // on a report that another collector is already keeping.
+        self.storage.lock()?;
+
+        let client = self.target.client()?;
+        self.collect(&client)
+    }
+
+    /// The rest of a collection cycle, once there's a client to request with.
+    fn collect(&mut self, client: &Client) -> ah::Result<()> {
+        let repository = client.repository(&self.target.owner, &self.target.repository);
+
+        let fetched_at = Utc::now();
+        let fetched = ApiDataReport::request(&repository)?;
+
+        // Archived before merging, so the report never holds anything that
+        // couldn't be rebuilt from the archive.
+        if let Some(archive) = &self.target.archive {
+            if fetched.modified || self.report.is_none() {
+                archive.append(fetched_at, Snapshot::Full(Box::new(fetched.data.clone())))?;
+            }
+        }
+
+        let modified = match &mut self.report {
+            Some(report) => report.merge(&fetched, fetched_at)?,
+            None => {
+                self.report = Some(RepositoryReport::new(&fetched.data, fetched_at)?);
+                true
+            }
+        };
+
+        // Saved regardless, in case the last save failed after a change. Only
+        // then are the responses cached, see StagedResponses.
+        self.save()?;
+        client.commit_responses();
+        save_etag_cache()?;
+
+        if modified {
+            info!("Updated report for {} at {}", self.name(), self.location());
+        } else {
+            info!("Nothing changed for {} since the last cycle", self.name());
+        }
+
+        Ok(())
+    }
+
+    pub fn save(&mut self) -> ah::Result<()> {
+        let Some(report) = &self.report else {
+            return Ok(());
+        };
+
+        self.storage.upsert_snapshot(report)
+    }
+
+    /// Where the report is stored.
+    pub fn location(&self) -> String {
+        self.storage.location()
+    }
+
+    /// The number of requests a collection cycle is expected to take: one for
+    /// each traffic endpoint, plus a page per hundred stargazers, watchers and
+    /// forks, going by the last cycle.
+    pub fn expected_requests(&self) -> u64 {
+        let pages = |items: usize| (items / 100 + 1) as u64;
+
+        let lists = match &self.report {
+            Some(report) => {
+                pages(report.stargazers.len())
+                    + pages(report.watchers.len())
+                    + pages(report.forks.len())
+            }
+            None => 3,
+        };
+
+        6 + lists
+    }
+
+    /// If the token doesn't have enough of its rate limit left for a whole
+    /// cycle, returns how long until it's replenished. Better to postpone the
+    /// cycle than to have it stall halfway through.
+    fn rate_limit_wait(&self) -> Option<Duration> {
+        let token = self
+            .target
+            .token
+            .resolve(&self.target.host, &self.target.requests)
+            .ok()?;
+        let rate_limit = rate_limit(&token)?;
+
+        (!rate_limit.allows(self.expected_requests()))
+            .then(|| rate_limit.until_reset() + Duration::from_secs(1))
+    }
+
+    fn retry_delay(&self, interval: Duration) -> Duration {
+        let exponent = self.consecutive_failures.saturating_sub(1).min(16);
+        let delay = BASE_RETRY_DELAY.saturating_mul(1 << exponent);
+
+        delay
+            .min(MAX_RETRY_DELAY)
+            .min(interval.max(BASE_RETRY_DELAY))
+    }
+}
+
+/// The background service. Keeps the reports of every configured repository
+/// up to date by requesting new data from the API every interval, and saving
+/// them back to disk after every successful cycle. Repositories are scheduled
+/// independently, so one failing doesn't hold back the others.
+pub struct Watcher {
+    config: Config,
+    storage: StorageBackend,
+    interval: Duration,
+    sampler: Option<MidnightSampler>,
+    repositories: Vec<WatchedRepository>,
+
+    /// When discovery is due next, if there's anything to discover.
+    next_discovery: Option<Instant>,
+}
+
+impl Watcher {
+    pub fn new(config: &Config) -> ah::Result<Self> {
+        let storage = StorageBackend::new(config)?;
+
+        let repositories = config
+            .targets()
+            .into_iter()
+            .map(|target| {
+                let target_storage = storage.open(&target);
+                WatchedRepository::new(target, target_storage)
+            })
+            .collect::<ah::Result<Vec<_>>>()?;
+
+        Ok(Watcher {
+            config: config.clone(),
+            storage,
+            interval: config.interval(),
+            sampler: config.sampling.then(MidnightSampler::default),
+            repositories,
+            next_discovery: (!config.discover.is_empty()).then(Instant::now),
+        })
+    }
+
+    pub fn repositories(&self) -> &Vec<WatchedRepository> {
+        &self.repositories
+    }
+
+    /// Discovers repositories, enrolling the new ones, and dropping the ones
+    /// that were discovered before but no longer are. Their reports are kept.
+    pub fn discover(&mut self) -> ah::Result<()> {
+        if self.config.discover.is_empty() {
+            return Ok(());
+        }
+
+        let discovered = match discover_targets(&self.config) {
+            Ok(discovered) => discovered,
+            Err(error) => {
+                self.next_discovery = Some(Instant::now() + self.interval);
+                return Err(error);
+            }
+        };
+
+        self.next_discovery = Some(Instant::now() + self.config.discovery_interval());
+
+        let wanted: HashSet<String> = self
+            .config
+            .targets()
+            .iter()
+            .chain(&discovered)
+            .map(|target| target.report_path.clone())
+            .collect();
+
+        self.repositories
+            .retain(|repository| wanted.contains(&repository.target.report_path));
+
+        for target in discovered {
+            let enrolled = self
+                .repositories
+                .iter()
+                .any(|repository| repository.target.report_path == target.report_path);
+
+            if enrolled {
+                continue;
+            }
+
+            let storage = self.storage.open(&target);
+
+            match WatchedRepository::new(target, storage) {
+                Ok(repository) => {
+                    info!("Enrolled {} for collection", repository.name());
+                    self.repositories.push(repository);
+                }
+                Err(error) => warn!("Not enrolling a discovered repository: {}", error),
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Performs a single collection cycle for every repository, failing if any
+    /// of them failed, but only after all of them have been attempted.
+    pub fn cycle(&mut self) -> ah::Result<()> {
+        let mut failures = 0;
+
+        if let Err(error) = self.discover() {
+            error!("{}", error);
+            failures += 1;
+        }
+
+        for repository in &mut self.repositories {
+            if let Err(error) = repository.cycle() {
+                error!(
+                    "Collection cycle for {} failed: {}",
+                    repository.name(),
+                    error
+                );
+                failures += 1;
+            }
+        }
+
+        if failures > 0 {
+            ah::bail!("{} failures during the collection cycle", failures);
+        }
+
+        Ok(())
+    }
+
+    /// Runs collection cycles forever. Failed cycles never touch the report on
+    /// disk; they're retried with an exponential backoff instead. When a UTC
+    /// midnight rollover comes up before the next cycle, it's sampled first.
+    pub fn run(&mut self) -> ah::Result<()> {
+        loop {
+            if self.next_discovery.is_some_and(|due| due <= Instant::now()) {
+                if let Err(error) = self.discover() {
+                    error!("{}", error);
+                }
+            }
+
+            let now = Instant::now();
+
+            for repository in &mut self.repositories {
+                if repository.next_cycle > now {
+                    continue;
+                }
+
+                if let Some(wait) = repository.rate_limit_wait() {
+                    info!(
+                        "Postponing the collection cycle for {} by {}s, until the rate limit resets",
+                        repository.name(),
+                        wait.as_secs()
+                    );
+
+                    repository.next_cycle = now + wait;
+                    continue;
+                }
+
+                let delay = match repository.cycle() {
+                    Ok(()) => {
+                        repository.consecutive_failures = 0;
+                        self.interval
+                    }
+                    Err(error) => {
+                        repository.consecutive_failures += 1;
+
+                        // Requests are already retried for transient failures, whereas
+                        // e.g. a missing repository or token without push access won't
+                        // fix itself any time soon, so there's no point hurrying.
+                        let permanent = error
+                            .downcast_ref::<ApiError>()
+                            .is_some_and(|error| !error.is_transient());
+
+                        let delay = if permanent {
+                            self.interval
+                        } else {
+                            repository.retry_delay(self.interval)
+                        };
+
+                        error!(
+                            "Collection cycle for {} failed ({} in a row), retrying in {}s: {}",
+                            repository.name(),
+                            repository.consecutive_failures,
+                            delay.as_secs(),
+                            error
+                        );
+
+                        delay
+                    }
+                };
+
+                repository.next_cycle = Instant::now() + delay;
+            }
+
+            let delay = self
+                .repositories
+                .iter()
+                .map(|repository| repository.next_cycle)
+                .chain(self.next_discovery)
+                .min()
+                .map(|next_cycle| next_cycle.saturating_duration_since(Instant::now()))
+                .unwrap_or(self.interval);
+
+            match self.sampler_start(delay) {
+                Some(wait) => {
+                    std::thread::sleep(wait);
+                    self.sample_rollover();
+                }
+                None => std::thread::sleep(delay),
+            }
+        }
+    }
+
+    fn sampler_start(&self, delay: Duration) -> Option<Duration> {
+        let sampler = self.sampler.as_ref()?;
+
+        sampler.starts_within(delay).unwrap_or_else(|error| {
+            error!("Failed to schedule the midnight sampler: {}", error);
+            None
+        })
+    }
+
+    fn sample_rollover(&mut self) {
+        let Some(sampler) = &self.sampler else {
+            return;
+        };
+
+        let mut targets = Vec::new();
+
+        for repository in &mut self.repositories {
+            // Without a report there is no pre-midnight snapshot to compare against.
+            let Some(report) = &mut repository.report else {
+                continue;
+            };
+
+            match repository.target.client() {
+                Ok(client) => targets.push(RolloverTarget {
+                    report,
+                    target: &repository.target,
+                    client,
+                    dropped: false,
+                }),
+                Err(error) => warn!(
+                    "Not sampling the midnight rollover for {}/{}: {}",
+                    repository.target.owner, repository.target.repository, error
+                ),
+            }
+        }
+
+        if let Err(error) = sampler.sample(&mut targets) {
+            error!("Failed to sample the midnight rollover: {}", error);
+        }
+
+        let mut clients = Vec::new();
+
+        for target in targets {
+            if target.dropped {
+                info!("Sampled the midnight rollover for {}", target.target.name());
+            } else {
+                info!(
+                    "No rollover observed for {} before the sampler deadline",
+                    target.target.name()
+                );
+            }
+
+            clients.push(target.client);
+        }
+
+        let mut saved = true;
+
+        for repository in &mut self.repositories {
+            if let Err(error) = repository.save() {
+                error!(
+                    "Failed to save the report for {} after sampling: {}",
+                    repository.name(),
+                    error
+                );
+                saved = false;
+            }
+        }
+
+        // Which report each response went into isn't tracked, so they're only
+        // cached if every report was saved.
+        if saved {
+            clients.iter().for_each(Client::commit_responses);
+        }
+
+        if let Err(error) = save_etag_cache() {
+            error!("Failed to save the ETag cache: {}", error);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::auth::*;
+    use crate::transport::*;
+    use std::sync::Arc;
+
+    #[test]
+    fn test_failed_cycle_is_collected_again() {
+        let dir = std::env::temp_dir().join(format!("failed-cycle-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+
+        let report_path = dir.join("octocat-hello-world.json");
+        let report_path = report_path.to_str().unwrap();
+
+        let fixtures = concat!(
+            env!("CARGO_MANIFEST_DIR"),
+            "/tests/fixtures/replay/octocat-hello-world"
+        );
+
+        let replay = Arc::new(ReplayTransport::load(fixtures).unwrap());
+
+        // A client per cycle, as RepositoryTarget::client makes them.
+        let client = || Client {
+            host: ApiHost::default(),
+            token: AuthToken("replay-failed-cycle".into()),
+            policy: RequestPolicy {
+                retries: 0,
+                ..Default::default()
+            },
+            transport: replay.clone(),
+            staged: Default::default(),
+        };
+
+        let target = RepositoryTarget {
+            owner: "octocat".into(),
+            repository: "hello-world".into(),
+            report_path: report_path.into(),
+            host: ApiHost::default(),
+            token: TokenSource::Ambient,
+            requests: RequestPolicy::default(),
+            archive: Some(Archive::for_report(report_path)),
+        };
+
+        let storage = Box::new(JsonFile {
+            file: ReportFile::new(report_path, 0),
+            encryption: None,
+        });
+
+        let mut repository = WatchedRepository::new(target, storage).unwrap();
+        repository.collect(&client()).unwrap();
+
+        let views =
+            |repository: &WatchedRepository| repository.report.as_ref().unwrap().total_views;
+        assert_eq!(views(&repository), 7);
+
+        replay.replace(
+            serde_json::from_value(serde_json::json!({
+                "url": "https://api.github.com/repos/octocat/hello-world/traffic/views?per=day",
+                "headers": { "ETag": "W/\"views-day-2\"" },
+                "body": {
+                    "count": 12,
+                    "uniques": 5,
+                    "views": [
+                        { "timestamp": "2023-10-14T00:00:00Z", "count": 3, "uniques": 2 },
+                        { "timestamp": "2023-10-15T00:00:00Z", "count": 4, "uniques": 2 },
+                        { "timestamp": "2023-10-16T00:00:00Z", "count": 5, "uniques": 1 }
+                    ]
+                }
+            }))
+            .unwrap(),
+        );
+
+        // A directory can't be appended to, so the cycle fails after fetching.
+        let archive = repository.target.archive.replace(Archive {
+            path: dir.to_str().unwrap().into(),
+        });
+
+        assert!(repository.collect(&client()).is_err());
+        assert_eq!(views(&repository), 7);
+
+        // The new views weren't cached, so they're requested and merged again.
+        repository.target.archive = archive;
+        repository.collect(&client()).unwrap();
+        assert_eq!(views(&repository), 12);
+
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+}